@@ -48,7 +48,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                             "DW_CFA_val_expression ({}, ...)",
                             gimli::X86_64::register_name(register).unwrap_or("{unknown}")
                         );
-                        display_val_expression(register, expression, &mut file)?;
+                        display_val_expression(register, expression, fde.cie().encoding(), &mut file)?;
                     }
                     Ok(None) => {
                         break;
@@ -100,12 +100,12 @@ impl Display for Val {
     }
 }
 
-fn display_val_expression<R>(target_reg: gimli::Register, exp: gimli::Expression<R>, w: &mut dyn Write) -> Result<(), Box<dyn std::error::Error>>
+fn display_val_expression<R>(target_reg: gimli::Register, exp: gimli::Expression<R>, encoding: gimli::Encoding, w: &mut dyn Write) -> Result<(), Box<dyn std::error::Error>>
     where
         R: gimli::Reader,
 {
     let mut val_generator = ValGenerator::new();
-    let mut ops = exp.operations(gimli::Encoding { address_size: 8, format: gimli::Format::Dwarf64, version: 5 });
+    let mut ops = exp.operations(encoding);
     let mut stack: Vec<Val> = Vec::new();
     writeln!(w, "uint64_t cal_{}(uint64_t r12, uint64_t r13, uint64_t r14, uint64_t r15){{", gimli::X86_64::register_name(target_reg).unwrap())?;
     writeln!(w, "    uint64_t rax=0,rbx=0;")?;
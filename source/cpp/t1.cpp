@@ -3,6 +3,9 @@
 #include <cstdlib>
 #include <stdio.h>
 
+// VM register names and the program, from `dwraf_generator --header dwraf.h`;
+// the flag is two hex words, text flags need t1_str.cpp
+#include "dwraf.h"
 
 void op(int8_t op){
   #include "dwraf.c"
@@ -16,34 +19,34 @@ void op(int8_t op){
 }
 
 int main(){
-  uint8_t opcode []={3,6,7,0,1,2,4,(uint8_t)-5,3,7,5};
+  uint8_t opcode []=VM_PROGRAM;
   uint64_t a,b,old_a,old_b;
   scanf("%lx%lx",&a,&b);
   old_a=a;
   old_b=b;
   asm(
-    "movq %0,%%r14\n"
-    "movq %1,%%r15\n"
-    ::"r"(a),"r"(b):"r15","r14"
+    "movq %0,%%" VM_FLAG_A "\n"
+    "movq %1,%%" VM_FLAG_B "\n"
+    ::"r"(a),"r"(b):VM_FLAG_B,VM_FLAG_A
   );
   uint64_t i=0;
-  asm("xor %%r13,%%r13":::"r13");
+  asm("xor %%" VM_ACC ",%%" VM_ACC:::VM_ACC);
   while(1){
     try{
-      asm("movq %0,%%r12"::"m"(opcode[i]):"r12");
+      asm("movq %0,%%" VM_PC::"m"(opcode[i]):VM_PC);
       op(opcode[i]);
     }catch(int a){
     }
-    uint64_t r12;
-    asm("mov %%r12,%0":"=m"(r12)::"r12");
-    if(r12==0||(i+=r12)>=sizeof(opcode)/sizeof(uint8_t)){
+    uint64_t pc;
+    asm("mov %%" VM_PC ",%0":"=m"(pc)::VM_PC);
+    if(pc==0||(i+=pc)>=sizeof(opcode)/sizeof(uint8_t)){
       break;
     }
   }
   uint64_t check;
-  asm("movq %%r13,%0\n":"=r"(check)::"r13");
+  asm("movq %%" VM_ACC ",%0\n":"=r"(check)::VM_ACC);
   if(check==0){
-    printf("Success! Your flag is flag{%016lx%016lx}\n",old_a,old_b);
+    printf("Success! Your flag is %s{%016lx%016lx}\n",VM_FLAG_PREFIX,old_a,old_b);
   }else{
     printf("Error\n");
   }
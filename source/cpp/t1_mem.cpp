@@ -0,0 +1,57 @@
+
+#include <cstdint>
+#include <cstdlib>
+#include <stdio.h>
+
+// VM register names and the program, from `dwraf_generator --words N --header dwraf.h`
+#include "dwraf.h"
+
+void op(int8_t op){
+  #include "dwraf.c"
+  try{
+    if(op>=0)
+      throw 1;
+  }catch(char*){
+  }
+
+  return;
+}
+
+int main(){
+  uint8_t opcode []=VM_PROGRAM;
+  // the rules load the block past the flag as well
+  uint64_t flag[VM_WORDS+VM_PADDING/8]={};
+  for(int k=0;k<VM_WORDS;k++){
+    if(scanf("%lx",&flag[k])!=1){
+      printf("Error\n");
+      return 0;
+    }
+  }
+  // the rules read the flag through VM_PTR, two words per block
+  asm("movq %0,%%" VM_PTR::"r"(flag):VM_PTR);
+  uint64_t i=0;
+  asm("xor %%" VM_ACC ",%%" VM_ACC:::VM_ACC);
+  while(1){
+    try{
+      asm("movq %0,%%" VM_PC::"m"(opcode[i]):VM_PC);
+      op(opcode[i]);
+    }catch(int a){
+    }
+    uint64_t pc;
+    asm("mov %%" VM_PC ",%0":"=m"(pc)::VM_PC);
+    if(pc==0||(i+=pc)>=sizeof(opcode)/sizeof(uint8_t)){
+      break;
+    }
+  }
+  uint64_t check;
+  asm("movq %%" VM_ACC ",%0\n":"=r"(check)::VM_ACC);
+  if(check==0){
+    printf("Success! Your flag is %s{",VM_FLAG_PREFIX);
+    for(int k=0;k<VM_WORDS;k++)
+      printf("%016lx",flag[k]);
+    printf("}\n");
+  }else{
+    printf("Error\n");
+  }
+  return 0;
+}
@@ -0,0 +1,48 @@
+#include <cstdint>
+#include <cstdlib>
+#include <stdio.h>
+
+// VM register names and the program, from `dwraf_generator --header dwraf.h`
+#include "dwraf.h"
+
+// op and its unwind info come from `dwraf_generator --format elf`
+extern "C" void op(int8_t op);
+
+extern "C" void dwraf_throw(){
+  throw 1;
+}
+
+int main(){
+  uint8_t opcode []=VM_PROGRAM;
+  uint64_t a,b,old_a,old_b;
+  scanf("%lx%lx",&a,&b);
+  old_a=a;
+  old_b=b;
+  asm(
+    "movq %0,%%" VM_FLAG_A "\n"
+    "movq %1,%%" VM_FLAG_B "\n"
+    ::"r"(a),"r"(b):VM_FLAG_B,VM_FLAG_A
+  );
+  uint64_t i=0;
+  asm("xor %%" VM_ACC ",%%" VM_ACC:::VM_ACC);
+  while(1){
+    try{
+      asm("movq %0,%%" VM_PC::"m"(opcode[i]):VM_PC);
+      op(opcode[i]);
+    }catch(int a){
+    }
+    uint64_t pc;
+    asm("mov %%" VM_PC ",%0":"=m"(pc)::VM_PC);
+    if(pc==0||(i+=pc)>=sizeof(opcode)/sizeof(uint8_t)){
+      break;
+    }
+  }
+  uint64_t check;
+  asm("movq %%" VM_ACC ",%0\n":"=r"(check)::VM_ACC);
+  if(check==0){
+    printf("Success! Your flag is %s{%016lx%016lx}\n",VM_FLAG_PREFIX,old_a,old_b);
+  }else{
+    printf("Error\n");
+  }
+  return 0;
+}
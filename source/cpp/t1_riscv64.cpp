@@ -0,0 +1,54 @@
+#include <cstdint>
+#include <cstdlib>
+#include <stdio.h>
+
+// riscv64 host for `dwraf_generator --arch riscv64`
+// VM register names and the program, from `dwraf_generator --header dwraf.h`
+#include "dwraf.h"
+
+void op(int8_t op){
+  #include "dwraf.c"
+  try{
+    if(op>=0)
+      throw 1;
+  }catch(char*){
+  }
+  
+  return;
+}
+
+int main(){
+  uint8_t opcode []=VM_PROGRAM;
+  uint64_t a,b,old_a,old_b;
+  scanf("%lx%lx",&a,&b);
+  old_a=a;
+  old_b=b;
+  asm(
+    "mv " VM_FLAG_A ",%0\n"
+    "mv " VM_FLAG_B ",%1\n"
+    ::"r"(a),"r"(b):VM_FLAG_A,VM_FLAG_B
+  );
+  uint64_t i=0;
+  asm("mv " VM_ACC ",zero":::VM_ACC);
+  while(1){
+    try{
+      // opcode[i] is unaligned, Linux emulates the misaligned ld
+      asm("ld " VM_PC ",0(%0)"::"r"(&opcode[i]):VM_PC);
+      op(opcode[i]);
+    }catch(int a){
+    }
+    uint64_t pc;
+    asm("mv %0," VM_PC:"=r"(pc)::VM_PC);
+    if(pc==0||(i+=pc)>=sizeof(opcode)/sizeof(uint8_t)){
+      break;
+    }
+  }
+  uint64_t check;
+  asm("mv %0," VM_ACC "\n":"=r"(check)::VM_ACC);
+  if(check==0){
+    printf("Success! Your flag is %s{%016lx%016lx}\n",VM_FLAG_PREFIX,old_a,old_b);
+  }else{
+    printf("Error\n");
+  }
+  return 0;
+}
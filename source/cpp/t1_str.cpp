@@ -0,0 +1,70 @@
+
+#include <cstdint>
+#include <cstdlib>
+#include <cstring>
+#include <stdio.h>
+
+// VM register names and the program, from
+// `dwraf_generator --flag-format printable:N --header dwraf.h`
+#include "dwraf.h"
+
+void op(int8_t op){
+  #include "dwraf.c"
+  try{
+    if(op>=0)
+      throw 1;
+  }catch(char*){
+  }
+
+  return;
+}
+
+int main(){
+  uint8_t opcode []=VM_PROGRAM;
+  char line[VM_WORDS*8+2];
+  // the flag, zero padded; the rules load the block past it as well
+  uint64_t flag[VM_WORDS+VM_PADDING/8]={};
+  if(!fgets(line,sizeof(line),stdin)){
+    printf("Error\n");
+    return 0;
+  }
+  size_t len=strcspn(line,"\n");
+  if(len>VM_WORDS*8){
+    printf("Error\n");
+    return 0;
+  }
+  // little-endian, the way the generator packs it
+  memcpy(flag,line,len);
+#if VM_WORDS>2
+  // the rules read the flag through VM_PTR, two words per block
+  asm("movq %0,%%" VM_PTR::"r"(flag):VM_PTR);
+#else
+  asm(
+    "movq %0,%%" VM_FLAG_A "\n"
+    "movq %1,%%" VM_FLAG_B "\n"
+    ::"r"(flag[0]),"r"(flag[1]):VM_FLAG_B,VM_FLAG_A
+  );
+#endif
+  uint64_t i=0;
+  asm("xor %%" VM_ACC ",%%" VM_ACC:::VM_ACC);
+  while(1){
+    try{
+      asm("movq %0,%%" VM_PC::"m"(opcode[i]):VM_PC);
+      op(opcode[i]);
+    }catch(int a){
+    }
+    uint64_t pc;
+    asm("mov %%" VM_PC ",%0":"=m"(pc)::VM_PC);
+    if(pc==0||(i+=pc)>=sizeof(opcode)/sizeof(uint8_t)){
+      break;
+    }
+  }
+  uint64_t check;
+  asm("movq %%" VM_ACC ",%0\n":"=r"(check)::VM_ACC);
+  if(check==0){
+    printf("Success! Your flag is %.*s\n",(int)len,line);
+  }else{
+    printf("Error\n");
+  }
+  return 0;
+}
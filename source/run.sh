@@ -1,8 +1,9 @@
 #!/bin/bash
 
 # cargo build --release
+# t1.cpp reads a hex flag; a --flag-format other than hex needs cpp/t1_str.cpp
 echo -n "nothing-$1        " >> flag.txt
-./target/release/dwraf_generator 2>>  flag.txt > cpp/dwraf.c
+./target/release/dwraf_generator ${2:+--seed "$2"} --flag-out flag.txt --header cpp/dwraf.h -o cpp/dwraf.c
 clang++ ./cpp/t1.cpp -o nothing-"$1"
 strip nothing-"$1"
-rm cpp/dwraf.c
+rm cpp/dwraf.c cpp/dwraf.h
@@ -0,0 +1,167 @@
+use std::fmt;
+use std::str::FromStr;
+
+use gimli::{AArch64, Register, RiscV, X86_64};
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Arch{
+    X86_64,
+    AArch64,
+    RiscV64,
+}
+
+/// The registers the VM keeps its state in.
+///
+/// `pc`, `acc`, `flag_a` and `flag_b` are written by the host harness and
+/// must survive the call into `op`, as must `ptr` for a flag in memory; the
+/// opaque registers are only ever read, for values that do not depend on what
+/// they hold, so they may be `ptr` as well.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct VmRegs{
+    /// the opcode and its immediates on entry, the pc delta after the handler
+    pub pc:Register,
+    /// the loop counter, then the result of the final compare
+    pub acc:Register,
+    pub flag_a:Register,
+    pub flag_b:Register,
+    /// the flag words still to check, for flags longer than two words
+    pub ptr:Register,
+    pub opaque_a:Register,
+    pub opaque_b:Register,
+}
+
+// callee-saved registers the harness can load with VM state
+const X86_64_STATE:[Register;5]=[X86_64::RBX, X86_64::R12, X86_64::R13, X86_64::R14, X86_64::R15];
+const AARCH64_STATE:[Register;10]=[
+    AArch64::X19, AArch64::X20, AArch64::X21, AArch64::X22, AArch64::X23,
+    AArch64::X24, AArch64::X25, AArch64::X26, AArch64::X27, AArch64::X28,
+];
+const RISCV64_STATE:[Register;11]=[
+    RiscV::S1, RiscV::S2, RiscV::S3, RiscV::S4, RiscV::S5, RiscV::S6,
+    RiscV::S7, RiscV::S8, RiscV::S9, RiscV::S10, RiscV::S11,
+];
+
+impl Arch{
+    pub const ALL:[Arch;3]=[Arch::X86_64,Arch::AArch64,Arch::RiscV64];
+
+    pub fn name(self)->&'static str{
+        match self{
+            Arch::X86_64=>"x86_64",
+            Arch::AArch64=>"aarch64",
+            Arch::RiscV64=>"riscv64",
+        }
+    }
+
+    /// The fixed register layout the original harnesses were written for.
+    pub fn vm_regs(self)->VmRegs{
+        match self{
+            Arch::X86_64=>VmRegs {
+                pc: X86_64::R12,
+                acc: X86_64::R13,
+                flag_a: X86_64::R14,
+                flag_b: X86_64::R15,
+                ptr: X86_64::RBX,
+                opaque_a: X86_64::RAX,
+                opaque_b: X86_64::RBX,
+            },
+            Arch::AArch64=>VmRegs {
+                pc: AArch64::X19,
+                acc: AArch64::X20,
+                flag_a: AArch64::X21,
+                flag_b: AArch64::X22,
+                ptr: AArch64::X25,
+                opaque_a: AArch64::X23,
+                opaque_b: AArch64::X24,
+            },
+            Arch::RiscV64=>VmRegs {
+                pc: RiscV::S1,
+                acc: RiscV::S2,
+                flag_a: RiscV::S3,
+                flag_b: RiscV::S4,
+                ptr: RiscV::S7,
+                opaque_a: RiscV::S5,
+                opaque_b: RiscV::S6,
+            },
+        }
+    }
+
+    /// Callee-saved registers that can hold any VM role.
+    pub fn state_registers(self)->&'static [Register]{
+        match self{
+            Arch::X86_64=>&X86_64_STATE,
+            Arch::AArch64=>&AARCH64_STATE,
+            Arch::RiscV64=>&RISCV64_STATE,
+        }
+    }
+
+    /// The frame pointer. It is callee-saved as well, but the harness cannot
+    /// load it, so it only ever serves as an opaque register.
+    pub fn frame_register(self)->Register{
+        match self{
+            Arch::X86_64=>X86_64::RBP,
+            Arch::AArch64=>AArch64::X29,
+            Arch::RiscV64=>RiscV::S0,
+        }
+    }
+
+    /// Draws a fresh mapping of the VM roles onto callee-saved registers.
+    pub fn random_vm_regs<R:Rng+?Sized>(self,rng:&mut R)->VmRegs{
+        let mut state=self.state_registers().to_vec();
+        state.shuffle(rng);
+        let mut opaque=state.split_off(4);
+        let ptr=opaque[0];
+        opaque.push(self.frame_register());
+        opaque.shuffle(rng);
+        VmRegs {
+            pc: state[0],
+            acc: state[1],
+            flag_a: state[2],
+            flag_b: state[3],
+            ptr,
+            opaque_a: opaque[0],
+            opaque_b: opaque[1],
+        }
+    }
+
+    pub fn register_name(self,register:Register)->Option<&'static str>{
+        match self{
+            Arch::X86_64=>X86_64::register_name(register),
+            Arch::AArch64=>AArch64::register_name(register),
+            Arch::RiscV64=>RiscV::register_name(register),
+        }
+    }
+}
+
+impl VmRegs{
+    /// Every register the rules may read; `ptr` can repeat an opaque one.
+    pub fn registers(&self)->[Register;7]{
+        [self.pc, self.acc, self.flag_a, self.flag_b, self.ptr, self.opaque_a, self.opaque_b]
+    }
+
+    /// A C header naming the registers the harness moves VM state through,
+    /// as string literals to paste into inline asm and clobber lists.
+    pub fn c_header(&self,arch:Arch)->String{
+        let name=|register:Register| arch.register_name(register).unwrap_or("?").to_ascii_lowercase();
+        let mut header=String::from("// generated by dwraf_generator, must match the rules in dwraf.c\n");
+        for (role,register) in [("VM_PC",self.pc),("VM_ACC",self.acc),("VM_FLAG_A",self.flag_a),("VM_FLAG_B",self.flag_b),("VM_PTR",self.ptr)] {
+            header+=&format!("#define {} \"{}\"\n",role,name(register));
+        }
+        header
+    }
+}
+
+impl fmt::Display for Arch{
+    fn fmt(&self,f:&mut fmt::Formatter<'_>)->fmt::Result{
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Arch{
+    type Err=String;
+
+    fn from_str(s:&str)->Result<Arch,String>{
+        Arch::ALL.iter().copied().find(|arch|arch.name()==s).ok_or_else(|| format!("unknown architecture `{}`",s))
+    }
+}
@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::isa::{Isa, Operand};
+
+/// Why a line of VM assembly does not assemble.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum AsmErrorKind{
+    UnknownMnemonic(String),
+    Operands{expected:usize,found:usize},
+    BadNumber(String),
+    OutOfRange(i64),
+    BadLabel(String),
+    DuplicateLabel(String),
+    UndefinedLabel(String),
+    /// a jump to somewhere other than the start of another instruction
+    BadTarget(i64),
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct AsmError{
+    pub line:usize,
+    pub kind:AsmErrorKind,
+}
+
+impl fmt::Display for AsmError{
+    fn fmt(&self,f:&mut fmt::Formatter<'_>)->fmt::Result{
+        write!(f, "line {}: ", self.line)?;
+        match &self.kind{
+            AsmErrorKind::UnknownMnemonic(name)=>write!(f, "unknown instruction `{}`", name),
+            AsmErrorKind::Operands{expected,found}=>write!(f, "expected {} operands, found {}", expected, found),
+            AsmErrorKind::BadNumber(token)=>write!(f, "invalid number `{}`", token),
+            AsmErrorKind::OutOfRange(value)=>write!(f, "{} does not fit in its operand", value),
+            AsmErrorKind::BadLabel(label)=>write!(f, "invalid label `{}`", label),
+            AsmErrorKind::DuplicateLabel(label)=>write!(f, "label `{}` is defined twice", label),
+            AsmErrorKind::UndefinedLabel(label)=>write!(f, "undefined label `{}`", label),
+            AsmErrorKind::BadTarget(offset)=>write!(f, "jump by {} does not land on another instruction", offset),
+        }
+    }
+}
+
+impl std::error::Error for AsmError{}
+
+// a statement and the line it is on
+enum Item<'a>{
+    Instruction{line:usize,opcode:u8,operands:Vec<(Operand,&'a str)>},
+    Bytes{line:usize,values:Vec<&'a str>},
+}
+
+fn parse_int(token:&str)->Option<i64>{
+    let (negative,digits)=match token.strip_prefix('-') {
+        Some(digits)=>(true,digits),
+        None=>(false,token),
+    };
+    let value=match digits.strip_prefix("0x") {
+        Some(hex)=>i64::from_str_radix(hex, 16).ok()?,
+        None=>digits.parse().ok()?,
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn is_label(name:&str)->bool{
+    name.starts_with(|c:char|c.is_ascii_alphabetic()||c=='_')&&name.chars().all(|c|c.is_ascii_alphanumeric()||c=='_')
+}
+
+// `value` as `size` little-endian bytes, read signed or unsigned
+fn pack(value:i64,size:usize,line:usize)->Result<Vec<u8>,AsmError>{
+    let bits=8*size as u32;
+    let fits=bits>=64||(-(1i64<<(bits-1))..(1i64<<bits)).contains(&value);
+    if !fits {
+        return Err(AsmError { line, kind: AsmErrorKind::OutOfRange(value) });
+    }
+    Ok(value.to_le_bytes()[..size].to_vec())
+}
+
+/// Assembles a VM program written with the mnemonics of `isa`.
+///
+/// Each line holds any number of `label:`s, then an instruction and its
+/// comma separated operands, or `.byte` and raw bytes; `;` starts a comment.
+/// A [`Operand::Target`] is a label or a distance from the opcode, and must
+/// land on another instruction: the harness halts on a distance of 0 and on
+/// leaving the program, which `halt` says plainly. Numbers may be negative
+/// or `0x` hex.
+pub fn assemble(isa:&Isa,source:&str)->Result<Vec<u8>,AsmError>{
+    let mut items=Vec::new();
+    // label -> item it names, or items.len() past the last one
+    let mut labels:HashMap<&str,usize>=HashMap::new();
+    for (line,text) in source.lines().enumerate() {
+        let line=line+1;
+        let mut text=text.split(';').next().unwrap().trim();
+        while let Some((label,rest))=text.split_once(':') {
+            let label=label.trim();
+            if !is_label(label) {
+                return Err(AsmError { line, kind: AsmErrorKind::BadLabel(label.to_string()) });
+            }
+            if labels.insert(label, items.len()).is_some() {
+                return Err(AsmError { line, kind: AsmErrorKind::DuplicateLabel(label.to_string()) });
+            }
+            text=rest.trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+        let (mnemonic,rest)=text.split_once(char::is_whitespace).unwrap_or((text,""));
+        let tokens:Vec<&str>=match rest.trim() {
+            ""=>Vec::new(),
+            rest=>rest.split(',').map(str::trim).collect(),
+        };
+        if mnemonic==".byte" {
+            items.push(Item::Bytes { line, values: tokens });
+            continue;
+        }
+        let instruction=isa.instruction(mnemonic)
+            .ok_or_else(|| AsmError { line, kind: AsmErrorKind::UnknownMnemonic(mnemonic.to_string()) })?;
+        if tokens.len()!=instruction.operands.len() {
+            return Err(AsmError { line, kind: AsmErrorKind::Operands { expected: instruction.operands.len(), found: tokens.len() } });
+        }
+        items.push(Item::Instruction { line, opcode: instruction.opcode, operands: instruction.operands.iter().copied().zip(tokens).collect() });
+    }
+
+    let mut addresses=Vec::with_capacity(items.len()+1);
+    let mut address=0;
+    for item in &items {
+        addresses.push(address);
+        address+=match item{
+            Item::Instruction{operands,..}=>1+operands.iter().map(|(operand,_)|operand.size()).sum::<usize>(),
+            Item::Bytes{values,..}=>values.len(),
+        };
+    }
+    addresses.push(address);
+    let starts:Vec<i64>=items.iter().zip(&addresses)
+        .filter(|(item,_)|matches!(item, Item::Instruction{..}))
+        .map(|(_,&address)|address as i64)
+        .collect();
+
+    let mut program=Vec::with_capacity(address);
+    for (item,&address) in items.iter().zip(&addresses) {
+        match item{
+            Item::Instruction{line,opcode,operands}=>{
+                let line=*line;
+                program.push(*opcode);
+                for &(operand,token) in operands {
+                    let value=match parse_int(token) {
+                        Some(value)=>value,
+                        None if operand==Operand::Target&&is_label(token)=>{
+                            let item=*labels.get(token).ok_or_else(|| AsmError { line, kind: AsmErrorKind::UndefinedLabel(token.to_string()) })?;
+                            addresses[item] as i64-address as i64
+                        }
+                        None=>return Err(AsmError { line, kind: AsmErrorKind::BadNumber(token.to_string()) }),
+                    };
+                    if operand==Operand::Target {
+                        // the handler sign extends the byte
+                        if !(-128..128).contains(&value) {
+                            return Err(AsmError { line, kind: AsmErrorKind::OutOfRange(value) });
+                        }
+                        if value==0||!starts.contains(&(address as i64+value)) {
+                            return Err(AsmError { line, kind: AsmErrorKind::BadTarget(value) });
+                        }
+                    }
+                    program.extend(pack(value, operand.size(), line)?);
+                }
+            }
+            Item::Bytes{line,values}=>{
+                for token in values {
+                    let value=parse_int(token).ok_or_else(|| AsmError { line: *line, kind: AsmErrorKind::BadNumber(token.to_string()) })?;
+                    program.extend(pack(value, 1, *line)?);
+                }
+            }
+        }
+    }
+    Ok(program)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::isa::Instruction;
+    use crate::vm::SOURCE;
+
+    // the built-in program's mnemonics; what the handlers do does not matter here
+    fn isa()->Isa{
+        Isa::new(vec![
+            Instruction::new(0, "count"),
+            Instruction::new(1, "step0"),
+            Instruction::new(2, "step1"),
+            Instruction::new(3, "whiten"),
+            Instruction::new(4, "jump").operand(Operand::Target),
+            Instruction::new(5, "halt"),
+            Instruction::new(6, "loop").operand(Operand::Target),
+            Instruction::new(7, "check"),
+            Instruction::new(9, "next").operand(Operand::Int(1)),
+            Instruction::new(11, "wide").operand(Operand::Int(2)),
+        ])
+    }
+
+    fn error(source:&str)->AsmError{
+        assemble(&isa(), source).unwrap_err()
+    }
+
+    #[test]
+    fn builtin_program(){
+        assert_eq!(assemble(&isa(), SOURCE).unwrap(), [3,6,7,0,1,2,4,(-5i8) as u8,3,7,5]);
+    }
+
+    #[test]
+    fn labels(){
+        let source="\
+start:  jump end        ; forward
+        .byte 1, 0xff, -1
+mid: back: loop start   ; two labels, backward
+end:
+        wide -2
+        jump mid
+";
+        assert_eq!(assemble(&isa(), source).unwrap(), [4,7,1,0xff,0xff,6,(-5i8) as u8,11,0xfe,0xff,4,(-5i8) as u8]);
+    }
+
+    #[test]
+    fn undefined_label(){
+        assert_eq!(error("halt\njump nowhere"), AsmError { line: 2, kind: AsmErrorKind::UndefinedLabel("nowhere".to_string()) });
+    }
+
+    #[test]
+    fn duplicate_label(){
+        assert_eq!(error("a: halt\na: halt"), AsmError { line: 2, kind: AsmErrorKind::DuplicateLabel("a".to_string()) });
+    }
+
+    #[test]
+    fn bad_targets(){
+        // into the loop's immediate
+        assert_eq!(error("loop 1\nhalt").kind, AsmErrorKind::BadTarget(1));
+        // onto itself, which halts
+        assert_eq!(error("jump 0").kind, AsmErrorKind::BadTarget(0));
+        assert_eq!(error("l: jump l").kind, AsmErrorKind::BadTarget(0));
+        // past the end, which halts too
+        assert_eq!(error("jump end\nhalt\nend:").kind, AsmErrorKind::BadTarget(3));
+        // onto data
+        assert_eq!(error("jump d\nd: .byte 5\nhalt").kind, AsmErrorKind::BadTarget(2));
+    }
+
+    #[test]
+    fn out_of_range(){
+        assert_eq!(error("loop 200").kind, AsmErrorKind::OutOfRange(200));
+        assert_eq!(error("next 256").kind, AsmErrorKind::OutOfRange(256));
+        assert_eq!(error("next -129").kind, AsmErrorKind::OutOfRange(-129));
+        assert_eq!(error("wide 0x10000").kind, AsmErrorKind::OutOfRange(0x10000));
+        assert_eq!(error(".byte 300").kind, AsmErrorKind::OutOfRange(300));
+        assert_eq!(assemble(&isa(), "next 255\nnext -128\nwide 0xffff").unwrap(), [9,0xff,9,0x80,11,0xff,0xff]);
+    }
+
+    #[test]
+    fn malformed(){
+        assert_eq!(error("fly").kind, AsmErrorKind::UnknownMnemonic("fly".to_string()));
+        assert_eq!(error("jump 1, 2").kind, AsmErrorKind::Operands { expected: 1, found: 2 });
+        assert_eq!(error("next k").kind, AsmErrorKind::BadNumber("k".to_string()));
+        assert_eq!(error("1a: halt").kind, AsmErrorKind::BadLabel("1a".to_string()));
+    }
+}
@@ -0,0 +1,821 @@
+use std::collections::VecDeque;
+use std::ops;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use gimli::write::Expression;
+use gimli::{DwOp, Register};
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use crate::junk;
+use crate::obfuscate::{exp_constu, exp_constu_from, expected_size, reg_size};
+use crate::opaque::OpaqueSources;
+
+static NEXT_VAR:AtomicUsize=AtomicUsize::new(0);
+
+// how often a budgeted build is planned again, and how close it must come to
+// stop early, as a fraction of the budget
+const FIT_TRIES:usize=8;
+const FIT_TOLERANCE:usize=50;
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum BinOp{
+    Add,
+    Sub,
+    Mul,
+    And,
+    Or,
+    Xor,
+    Shl,
+    Shr,
+    Shra,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl BinOp{
+    pub fn dw_op(self)->DwOp{
+        match self{
+            BinOp::Add=>gimli::DW_OP_plus,
+            BinOp::Sub=>gimli::DW_OP_minus,
+            BinOp::Mul=>gimli::DW_OP_mul,
+            BinOp::And=>gimli::DW_OP_and,
+            BinOp::Or=>gimli::DW_OP_or,
+            BinOp::Xor=>gimli::DW_OP_xor,
+            BinOp::Shl=>gimli::DW_OP_shl,
+            BinOp::Shr=>gimli::DW_OP_shr,
+            BinOp::Shra=>gimli::DW_OP_shra,
+            BinOp::Eq=>gimli::DW_OP_eq,
+            BinOp::Ne=>gimli::DW_OP_ne,
+            BinOp::Lt=>gimli::DW_OP_lt,
+            BinOp::Le=>gimli::DW_OP_le,
+            BinOp::Gt=>gimli::DW_OP_gt,
+            BinOp::Ge=>gimli::DW_OP_ge,
+        }
+    }
+
+    /// The operation that gives the same result with the operands exchanged,
+    /// if there is one.
+    pub fn mirror(self)->Option<BinOp>{
+        match self{
+            BinOp::Add|BinOp::Mul|BinOp::And|BinOp::Or|BinOp::Xor|BinOp::Eq|BinOp::Ne=>Some(self),
+            BinOp::Lt=>Some(BinOp::Gt),
+            BinOp::Le=>Some(BinOp::Ge),
+            BinOp::Gt=>Some(BinOp::Lt),
+            BinOp::Ge=>Some(BinOp::Le),
+            BinOp::Sub|BinOp::Shl|BinOp::Shr|BinOp::Shra=>None,
+        }
+    }
+
+    /// The comparison that holds exactly when `self` does not, if `self` is one.
+    pub fn negate(self)->Option<BinOp>{
+        match self{
+            BinOp::Eq=>Some(BinOp::Ne),
+            BinOp::Ne=>Some(BinOp::Eq),
+            BinOp::Lt=>Some(BinOp::Ge),
+            BinOp::Le=>Some(BinOp::Gt),
+            BinOp::Gt=>Some(BinOp::Le),
+            BinOp::Ge=>Some(BinOp::Lt),
+            _=>None,
+        }
+    }
+}
+
+/// An expression over the unwound registers and u64 literals.
+///
+/// Comparisons follow DWARF semantics: they yield 0 or 1 and compare the
+/// operands as signed values.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum Expr{
+    Reg(Register),
+    Const(u64),
+    Var(usize),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    Bin(BinOp,Box<Expr>,Box<Expr>),
+    /// the 8 bytes at an address
+    Deref(Box<Expr>),
+    /// `cond != 0 ? then : else`
+    Select(Box<Expr>,Box<Expr>,Box<Expr>),
+    /// Evaluates the value once and makes it available to the body as `Var`.
+    Let(usize,Box<Expr>,Box<Expr>),
+}
+
+impl Expr{
+    pub fn reg(register:Register)->Expr{
+        Expr::Reg(register)
+    }
+
+    pub fn constant(value:u64)->Expr{
+        Expr::Const(value)
+    }
+
+    pub fn bin(op:BinOp,lhs:impl Into<Expr>,rhs:impl Into<Expr>)->Expr{
+        Expr::Bin(op,Box::new(lhs.into()),Box::new(rhs.into()))
+    }
+
+    pub fn deref(address:impl Into<Expr>)->Expr{
+        Expr::Deref(Box::new(address.into()))
+    }
+
+    pub fn select(cond:impl Into<Expr>,then:impl Into<Expr>,otherwise:impl Into<Expr>)->Expr{
+        Expr::Select(Box::new(cond.into()),Box::new(then.into()),Box::new(otherwise.into()))
+    }
+
+    /// Binds `value` so `body` can use it any number of times while it is
+    /// only evaluated once.
+    pub fn bind(value:impl Into<Expr>,body:impl FnOnce(Expr)->Expr)->Expr{
+        let id=NEXT_VAR.fetch_add(1, Ordering::Relaxed);
+        let body=body(Expr::Var(id));
+        Expr::Let(id,Box::new(value.into()),Box::new(body))
+    }
+
+    pub fn shra(self,rhs:impl Into<Expr>)->Expr{
+        Expr::bin(BinOp::Shra,self,rhs)
+    }
+
+    pub fn eq(self,rhs:impl Into<Expr>)->Expr{
+        Expr::bin(BinOp::Eq,self,rhs)
+    }
+
+    pub fn ne(self,rhs:impl Into<Expr>)->Expr{
+        Expr::bin(BinOp::Ne,self,rhs)
+    }
+
+    pub fn lt(self,rhs:impl Into<Expr>)->Expr{
+        Expr::bin(BinOp::Lt,self,rhs)
+    }
+
+    pub fn le(self,rhs:impl Into<Expr>)->Expr{
+        Expr::bin(BinOp::Le,self,rhs)
+    }
+
+    pub fn gt(self,rhs:impl Into<Expr>)->Expr{
+        Expr::bin(BinOp::Gt,self,rhs)
+    }
+
+    pub fn ge(self,rhs:impl Into<Expr>)->Expr{
+        Expr::bin(BinOp::Ge,self,rhs)
+    }
+}
+
+impl From<u64> for Expr{
+    fn from(value:u64)->Expr{
+        Expr::Const(value)
+    }
+}
+
+impl From<Register> for Expr{
+    fn from(register:Register)->Expr{
+        Expr::Reg(register)
+    }
+}
+
+macro_rules! bin_op_impl {
+    ($($tr:ident $method:ident $op:ident),*) => {$(
+        impl<T:Into<Expr>> ops::$tr<T> for Expr{
+            type Output=Expr;
+            fn $method(self,rhs:T)->Expr{
+                Expr::bin(BinOp::$op,self,rhs)
+            }
+        }
+    )*};
+}
+
+bin_op_impl!(Add add Add, Sub sub Sub, Mul mul Mul, BitAnd bitand And, BitOr bitor Or, BitXor bitxor Xor, Shl shl Shl, Shr shr Shr);
+
+impl ops::Not for Expr{
+    type Output=Expr;
+    fn not(self)->Expr{
+        Expr::Not(Box::new(self))
+    }
+}
+
+impl ops::Neg for Expr{
+    type Output=Expr;
+    fn neg(self)->Expr{
+        Expr::Neg(Box::new(self))
+    }
+}
+
+/// How much the builder spends on obfuscating literals.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Budget{
+    /// every literal goes through [`exp_constu`] at this depth
+    Depth(u64),
+    /// about this many expression bytes per rule
+    PerRule(usize),
+    /// about this many expression bytes for all the rules of one `build_all`,
+    /// which go deeper evenly over every literal, so a rule with more
+    /// literals gets more of them
+    Total(usize),
+}
+
+/// Lowers [`Expr`] trees to DWARF expressions, tracking the stack layout so
+/// that callers never have to.
+///
+/// Every literal goes through [`exp_constu`], either at a fixed depth or at
+/// depths planned to fill a byte [`Budget`]. With [`opaque`](Self::opaque)
+/// registers it also draws on opaque constants and predicates built from
+/// them and from the CFA, and with [`branches`](Self::branches) it lowers
+/// selects to jumps. [`junk`](Self::junk) sprinkles neutral operations
+/// between the ones that compute something.
+pub struct ExpressionBuilder<'a,R:Rng+?Sized>{
+    rng:&'a mut R,
+    budget:Budget,
+    // planned depths of the literals still to be emitted, in emission order
+    depths:VecDeque<u64>,
+    // collects the literals and their sizes instead of obfuscating them while
+    // sizing a tree
+    literals:Option<Vec<(u64,usize)>>,
+    opaque:Option<Vec<Register>>,
+    branches:bool,
+    junk:f64,
+    size:usize,
+    height:usize,
+    vars:Vec<(usize,usize)>,
+    // chains of the tree being lowered whose jumps did not fit, which get masks
+    masked:Vec<*const Expr>,
+    // whether a chain has just been found not to fit, so the tree is lowered again
+    too_far:bool,
+    // draws the operand orders, decoys, dispatcher layouts and junk of the tree
+    // being lowered
+    layout:ChaCha8Rng,
+}
+
+impl<'a,R:Rng+?Sized> ExpressionBuilder<'a,R>{
+    pub fn new(rng:&'a mut R,depth:u64)->Self{
+        ExpressionBuilder::with_budget(rng, Budget::Depth(depth))
+    }
+
+    pub fn with_budget(rng:&'a mut R,budget:Budget)->Self{
+        ExpressionBuilder { rng, budget, depths: VecDeque::new(), literals: None, opaque: None, branches: false, junk: 0.0, size: 0, height: 0, vars: Vec::new(), masked: Vec::new(), too_far: false, layout: ChaCha8Rng::seed_from_u64(0) }
+    }
+
+    /// Lets literals hide behind opaque gadgets reading `registers`, which
+    /// must all be readable when the rules run, or if it is empty just the
+    /// CFA, which is only in reach near the bottom of the stack.
+    pub fn opaque(mut self,registers:Vec<Register>)->Self{
+        self.opaque=Some(registers);
+        self
+    }
+
+    /// Lowers chains of selects to a dispatcher jumping over the arms with
+    /// `DW_OP_bra` and `DW_OP_skip`, with a dead arm in each, instead of
+    /// running every arm and masking the results. Chains too long for 16-bit
+    /// jumps still get the masks.
+    pub fn branches(mut self)->Self{
+        self.branches=true;
+        self
+    }
+
+    /// Follows each node of the tree with a neutral sequence such as
+    /// `DW_OP_dup; DW_OP_drop` with probability `rate`, from 0 to 1.
+    pub fn junk(mut self,rate:f64)->Self{
+        assert!((0.0..=1.0).contains(&rate), "junk rate {} is not a probability", rate);
+        self.junk=rate;
+        self
+    }
+
+    /// Builds an expression that leaves the value of `expr` on top of the stack.
+    pub fn build(&mut self,expr:&Expr)->Expression{
+        self.build_all(std::slice::from_ref(expr)).remove(0)
+    }
+
+    /// Builds several expressions, sharing a [`Budget::Total`] between them.
+    pub fn build_all(&mut self,exprs:&[Expr])->Vec<Expression>{
+        // a seed per tree for how it is laid out, so sizing it and lowering
+        // it make the same choices
+        let seeds:Vec<u64>=exprs.iter().map(|_|self.rng.gen()).collect();
+        self.depths.clear();
+        match self.budget{
+            Budget::Depth(_)=>exprs.iter().zip(seeds).map(|(expr,seed)|self.lower(expr, seed)).collect(),
+            Budget::PerRule(bytes)=>exprs.iter().zip(seeds).flat_map(|(expr,seed)|self.fit(std::slice::from_ref(expr), &[seed], bytes)).collect(),
+            Budget::Total(bytes)=>self.fit(exprs, &seeds, bytes),
+        }
+    }
+
+    // Lowers `exprs` with literal depths planned to take `bytes` between
+    // them, then plans again against the size that came out, keeping the
+    // closest of a few tries: a deep literal varies too much in size to hit
+    // the budget from the expected sizes alone.
+    fn fit(&mut self,exprs:&[Expr],seeds:&[u64],bytes:usize)->Vec<Expression>{
+        let mut fixed=0;
+        let mut literals=Vec::new();
+        for (expr,&seed) in exprs.iter().zip(seeds) {
+            let (size,values)=self.measure(expr, seed);
+            fixed+=size;
+            literals.extend(values);
+        }
+        let wanted=bytes.saturating_sub(fixed);
+        let mut budget=wanted as f64;
+        let mut best:Option<(usize,Vec<Expression>)>=None;
+        for _ in 0..FIT_TRIES {
+            self.depths=self.plan(&literals, budget as usize).into();
+            let mut size=0;
+            let built:Vec<Expression>=exprs.iter().zip(seeds).map(|(expr,&seed)| {
+                let exp=self.lower(expr, seed);
+                size+=self.size;
+                exp
+            }).collect();
+            let miss=size.abs_diff(bytes);
+            if best.as_ref().is_none_or(|(closest,_)|miss<*closest) {
+                best=Some((miss,built));
+            }
+            if miss<=bytes/FIT_TOLERANCE {
+                break;
+            }
+            budget*=wanted as f64/size.saturating_sub(fixed).max(1) as f64;
+        }
+        best.unwrap().1
+    }
+
+    fn lower(&mut self,expr:&Expr,seed:u64)->Expression{
+        self.masked.clear();
+        let (depths,literals)=(self.depths.clone(),self.literals.as_ref().map(Vec::len));
+        loop {
+            let mut exp=Expression::new();
+            self.layout=ChaCha8Rng::seed_from_u64(seed);
+            self.size=0;
+            self.height=0;
+            self.vars.clear();
+            self.too_far=false;
+            self.emit(&mut exp, expr);
+            if !self.too_far {
+                return exp;
+            }
+            // again with masks for that chain, from the same literals
+            self.depths=depths.clone();
+            if let (Some(seen),Some(len))=(&mut self.literals,literals) {
+                seen.truncate(len);
+            }
+        }
+    }
+
+    // the bytes `expr` takes besides its literals, and the literals in emission order
+    fn measure(&mut self,expr:&Expr,seed:u64)->(usize,Vec<u64>){
+        self.literals=Some(Vec::new());
+        self.lower(expr, seed);
+        let literals=self.literals.take().unwrap();
+        let fixed=self.size-literals.iter().map(|&(_,size)|size).sum::<usize>();
+        (fixed,literals.into_iter().map(|(value,_)|value).collect())
+    }
+
+    // Deepens the literals one level at a time, each round in a fresh random
+    // order, until the next step would overrun `budget`; so depths never differ
+    // by more than one.
+    fn plan(&mut self,literals:&[u64],budget:usize)->Vec<u64>{
+        let mut depths=vec![0;literals.len()];
+        let opaque=self.opaque.is_some();
+        let mut spent:f64=literals.iter().map(|&value|expected_size(value, 0, opaque)).sum();
+        let mut order:Vec<usize>=(0..literals.len()).collect();
+        loop {
+            order.shuffle(self.rng);
+            for &i in &order {
+                let cost=expected_size(literals[i], depths[i]+1, opaque)-expected_size(literals[i], depths[i], opaque);
+                if spent+cost>budget as f64 {
+                    return depths;
+                }
+                depths[i]+=1;
+                spent+=cost;
+            }
+            if order.is_empty() {
+                return depths;
+            }
+        }
+    }
+
+    fn op(&mut self,exp:&mut Expression,op:DwOp,pops:usize,pushes:usize){
+        exp.op(op);
+        self.size+=1;
+        // DW_OP_dup and DW_OP_over may copy the CFA under the values we pushed
+        self.height=self.height+pushes-pops;
+    }
+
+    fn constu(&mut self,exp:&mut Expression,value:u64){
+        let depth=match (&self.literals,self.budget){
+            (Some(_),_)=>0,
+            (None,Budget::Depth(depth))=>depth,
+            (None,_)=>self.depths.pop_front().expect("literal missing from the plan"),
+        };
+        let size=match &self.opaque{
+            // the CFA sits right below everything the builder pushed
+            Some(registers)=>exp_constu_from(exp, self.rng, value, depth, OpaqueSources { registers, cfa_depth: Some(self.height) }),
+            None=>exp_constu(exp, self.rng, value, depth),
+        };
+        if let Some(literals)=&mut self.literals {
+            literals.push((value,size));
+        }
+        self.size+=size;
+        self.height+=1;
+    }
+
+    fn emit(&mut self,exp:&mut Expression,expr:&Expr){
+        match expr{
+            Expr::Reg(register)=>{
+                exp.op_reg(*register);
+                self.size+=reg_size(*register);
+                self.height+=1;
+            }
+            Expr::Const(value)=>self.constu(exp, *value),
+            Expr::Var(id)=>{
+                let (_,slot)=*self.vars.iter().rev().find(|(var,_)|var==id).expect("unbound variable");
+                let index=self.height-1-slot;
+                exp.op_pick(u8::try_from(index).expect("variable is too deep in the stack"));
+                // DW_OP_dup and DW_OP_over have no operand
+                self.size+=if index<2 { 1 } else { 2 };
+                self.height+=1;
+            }
+            Expr::Not(value)=>{
+                self.emit(exp, value);
+                self.op(exp, gimli::DW_OP_not, 1, 1);
+            }
+            Expr::Neg(value)=>{
+                self.emit(exp, value);
+                self.op(exp, gimli::DW_OP_neg, 1, 1);
+            }
+            Expr::Deref(address)=>{
+                self.emit(exp, address);
+                self.op(exp, gimli::DW_OP_deref, 1, 1);
+            }
+            Expr::Bin(op,lhs,rhs)=>self.binary(exp, *op, lhs, rhs),
+            Expr::Select(..) if self.branches=>self.chain(exp, expr),
+            Expr::Select(cond,then,otherwise)=>{
+                self.mask(exp, cond);
+                if let Expr::Const(0)=**otherwise {
+                    self.emit(exp, then);
+                    self.op(exp, gimli::DW_OP_and, 2, 1);
+                    return;
+                }
+                // mask | mask & then | ~mask & otherwise
+                self.op(exp, gimli::DW_OP_dup, 1, 2);
+                self.emit(exp, then);
+                self.op(exp, gimli::DW_OP_and, 2, 1);
+                self.op(exp, gimli::DW_OP_swap, 2, 2);
+                self.op(exp, gimli::DW_OP_not, 1, 1);
+                self.emit(exp, otherwise);
+                self.op(exp, gimli::DW_OP_and, 2, 1);
+                self.op(exp, gimli::DW_OP_or, 2, 1);
+            }
+            Expr::Let(id,value,body)=>{
+                self.emit(exp, value);
+                self.vars.push((*id,self.height-1));
+                self.emit(exp, body);
+                self.vars.pop();
+                self.op(exp, gimli::DW_OP_swap, 2, 2);
+                self.op(exp, gimli::DW_OP_drop, 1, 0);
+            }
+        }
+        if self.junk>0.0&&self.layout.gen_bool(self.junk) {
+            self.size+=junk::neutral(exp, &mut self.layout, self.height);
+        }
+    }
+
+    // a select and the selects in its else arm, c1 ? a1 : c2 ? a2 : ... : default,
+    // with a decoy arm whose mask is zero. For a chain at height h the default
+    // runs at h, the masks at h+1 and the arms at h+2 in both layouts.
+    fn chain(&mut self,exp:&mut Expression,expr:&Expr){
+        let key=expr as *const Expr;
+        let mut tests:Vec<(Option<&Expr>,&Expr)>=Vec::new();
+        let mut default=expr;
+        while let Expr::Select(cond,then,otherwise)=default {
+            tests.push((Some(&**cond),&**then));
+            default=otherwise;
+        }
+        // the same number of literals every time so sizing a tree still sees them all
+        let op=*[BinOp::Add,BinOp::Sub,BinOp::Xor].choose(&mut self.layout).unwrap();
+        let decoy=Expr::bin(op, self.layout.gen::<u64>(), self.layout.gen::<u64>());
+        let at=self.layout.gen_range(0..=tests.len() as u32) as usize;
+        tests.insert(at, (None,&decoy));
+        if self.masked.contains(&key) {
+            self.straight(exp, &tests, default);
+        } else if !self.flattened(exp, &tests, default) {
+            self.masked.push(key);
+            self.too_far=true;
+        }
+    }
+
+    // the test of an arm of a chain, all ones when it is taken
+    fn test(&mut self,exp:&mut Expression,cond:Option<&Expr>){
+        match cond{
+            Some(cond)=>self.mask(exp, cond),
+            None=>self.constu(exp, 0),
+        }
+    }
+
+    // Straight-line masks: every mask and arm runs and the results are merged
+    // with `mask & arm | ~mask & rest`, from the last arm up.
+    fn straight(&mut self,exp:&mut Expression,tests:&[(Option<&Expr>,&Expr)],default:&Expr){
+        self.emit(exp, default);
+        for &(cond,arm) in tests.iter().rev() {
+            self.test(exp, cond);
+            self.emit(exp, arm);
+            // rest mask arm -> (arm & mask) (rest & ~mask)
+            self.op(exp, gimli::DW_OP_over, 2, 3);
+            self.op(exp, gimli::DW_OP_and, 2, 1);
+            self.op(exp, gimli::DW_OP_rot, 3, 3);
+            self.op(exp, gimli::DW_OP_not, 1, 1);
+            self.op(exp, gimli::DW_OP_and, 2, 1);
+            self.op(exp, gimli::DW_OP_or, 2, 1);
+        }
+    }
+
+    // A dispatcher that tests the masks in order and jumps to the first arm
+    // whose mask is set, with the arms and the default laid out after it in
+    // random order. It keeps a spare value under each mask and the taken mask
+    // under the arm, so every piece runs at the height `straight` gives it.
+    // False if a jump does not fit in the 16 bits DW_OP_bra and DW_OP_skip have.
+    fn flattened(&mut self,exp:&mut Expression,tests:&[(Option<&Expr>,&Expr)],default:&Expr)->bool{
+        let height=self.height;
+        // (operation, byte offset past it, block it jumps to); block 0 is the
+        // default, 1..=n the arms and n+1 the end
+        let mut jumps=Vec::new();
+        self.op(exp, gimli::DW_OP_dup, 1, 2);
+        for (i,&(cond,_)) in tests.iter().enumerate() {
+            self.test(exp, cond);
+            self.op(exp, gimli::DW_OP_dup, 1, 2);
+            let jump=exp.op_bra();
+            self.size+=3;
+            self.height-=1;
+            jumps.push((jump,self.size,i+1));
+            self.op(exp, gimli::DW_OP_drop, 1, 0);
+        }
+        self.op(exp, gimli::DW_OP_drop, 1, 0);
+
+        let end=tests.len()+1;
+        let mut order:Vec<usize>=(0..end).collect();
+        order.shuffle(&mut self.layout);
+        if order[0]!=0 {
+            let jump=exp.op_skip();
+            self.size+=3;
+            jumps.push((jump,self.size,0));
+        }
+        // (operation, byte offset) each block starts at
+        let mut blocks=vec![(0,0);end+1];
+        for (k,&block) in order.iter().enumerate() {
+            blocks[block]=(exp.next_index(),self.size);
+            match block{
+                0=>{
+                    self.height=height;
+                    self.emit(exp, default);
+                }
+                _=>{
+                    self.height=height+2;
+                    self.emit(exp, tests[block-1].1);
+                    // drop the spare value and the mask under the result
+                    self.op(exp, gimli::DW_OP_rot, 3, 3);
+                    self.op(exp, gimli::DW_OP_drop, 1, 0);
+                    self.op(exp, gimli::DW_OP_drop, 1, 0);
+                }
+            }
+            if k+1<order.len() {
+                let jump=exp.op_skip();
+                self.size+=3;
+                jumps.push((jump,self.size,end));
+            }
+        }
+        blocks[end]=(exp.next_index(),self.size);
+        self.height=height+1;
+        jumps.into_iter().all(|(jump,from,block)| {
+            let (target,to)=blocks[block];
+            exp.set_target(jump, target);
+            i16::try_from(to as i64-from as i64).is_ok()
+        })
+    }
+
+    // `lhs op rhs`, with the operands pushed in random order: exchanged ones
+    // get the mirrored operation or a DW_OP_swap
+    fn binary(&mut self,exp:&mut Expression,op:BinOp,lhs:&Expr,rhs:&Expr){
+        if self.layout.gen() {
+            self.emit(exp, lhs);
+            self.emit(exp, rhs);
+            self.op(exp, op.dw_op(), 2, 1);
+            return;
+        }
+        self.emit(exp, rhs);
+        self.emit(exp, lhs);
+        match op.mirror(){
+            Some(mirror) if self.layout.gen()=>self.op(exp, mirror.dw_op(), 2, 1),
+            _=>{
+                self.op(exp, gimli::DW_OP_swap, 2, 2);
+                self.op(exp, op.dw_op(), 2, 1);
+            }
+        }
+    }
+
+    // all ones if cond holds, zero otherwise: ((!cond) - 1)
+    fn mask(&mut self,exp:&mut Expression,cond:&Expr){
+        match cond{
+            Expr::Bin(op,lhs,rhs) if op.negate().is_some()=>self.binary(exp, op.negate().unwrap(), lhs, rhs),
+            _=>{
+                self.emit(exp, cond);
+                self.constu(exp, 0);
+                self.op(exp, gimli::DW_OP_eq, 2, 1);
+            }
+        }
+        self.constu(exp, 1);
+        self.op(exp, gimli::DW_OP_minus, 2, 1);
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use gimli::read::{EndianSlice, Operation};
+    use gimli::LittleEndian;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::cfi::{expression_bytes, DEFAULT_ENCODING};
+    use crate::eval::{evaluate, Memory, Registers};
+    use crate::stack::analyze;
+
+    const A:Register=Register(3);
+    const B:Register=Register(12);
+
+    fn a()->Expr{
+        Expr::reg(A)
+    }
+
+    fn b()->Expr{
+        Expr::reg(B)
+    }
+
+    // mostly random, but the values at the edges of the arithmetic too
+    fn value(rng:&mut ChaCha8Rng)->u64{
+        *[0,1,!0,1<<63,(1<<63)-1,rng.gen(),rng.gen(),rng.gen()].choose(rng).unwrap()
+    }
+
+    // the bytecode of `exp`, after checking it leaves exactly one value over the CFA
+    fn bytes(exp:&Expression)->Vec<u8>{
+        let bytecode=expression_bytes(exp, DEFAULT_ENCODING).unwrap();
+        assert_eq!(analyze(&bytecode, DEFAULT_ENCODING).unwrap().final_depth, 2);
+        bytecode
+    }
+
+    fn run(bytecode:&[u8],x:u64,y:u64,cfa:u64)->u64{
+        let regs:Registers=[(A,x),(B,y)].into_iter().collect();
+        evaluate(bytecode, DEFAULT_ENCODING, &regs, &Memory::new(), cfa).unwrap()
+    }
+
+    // builds `expr` a number of times, with and without opaque gadgets, and
+    // checks each against `expected` for random register values
+    fn check(expr:&Expr,configure:fn(ExpressionBuilder<'_,ChaCha8Rng>)->ExpressionBuilder<'_,ChaCha8Rng>,expected:impl Fn(u64,u64)->u64){
+        for seed in 0..32 {
+            let mut rng=ChaCha8Rng::seed_from_u64(seed);
+            let bytecode={
+                let mut builder=configure(ExpressionBuilder::new(&mut rng, 2).junk(0.2));
+                if seed%2==1 {
+                    builder=builder.opaque(vec![A,B]);
+                }
+                bytes(&builder.build(expr))
+            };
+            for _ in 0..8 {
+                let (x,y,cfa)=(value(&mut rng),value(&mut rng),rng.gen());
+                assert_eq!(run(&bytecode, x, y, cfa), expected(x, y), "seed {} with {:#x} and {:#x}", seed, x, y);
+            }
+        }
+    }
+
+    fn has_branch(bytecode:&[u8])->bool{
+        let mut reader=EndianSlice::new(bytecode, LittleEndian);
+        while !reader.is_empty() {
+            if let Operation::Bra { .. }=Operation::parse(&mut reader, DEFAULT_ENCODING).unwrap() {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn apply(op:BinOp,x:u64,y:u64)->u64{
+        let (sx,sy)=(x as i64,y as i64);
+        match op{
+            BinOp::Add=>x.wrapping_add(y),
+            BinOp::Sub=>x.wrapping_sub(y),
+            BinOp::Mul=>x.wrapping_mul(y),
+            BinOp::And=>x&y,
+            BinOp::Or=>x|y,
+            BinOp::Xor=>x^y,
+            BinOp::Shl=>x.wrapping_shl(y as u32),
+            BinOp::Shr=>x.wrapping_shr(y as u32),
+            BinOp::Shra=>sx.wrapping_shr(y as u32) as u64,
+            BinOp::Eq=>(x==y) as u64,
+            BinOp::Ne=>(x!=y) as u64,
+            BinOp::Lt=>(sx<sy) as u64,
+            BinOp::Le=>(sx<=sy) as u64,
+            BinOp::Gt=>(sx>sy) as u64,
+            BinOp::Ge=>(sx>=sy) as u64,
+        }
+    }
+
+    const OPS:[BinOp;15]=[BinOp::Add,BinOp::Sub,BinOp::Mul,BinOp::And,BinOp::Or,BinOp::Xor,BinOp::Shl,BinOp::Shr,BinOp::Shra,
+        BinOp::Eq,BinOp::Ne,BinOp::Lt,BinOp::Le,BinOp::Gt,BinOp::Ge];
+
+    #[test]
+    fn binary_operand_orders(){
+        // the seeds push the operands both ways, with the mirrored operation or a swap
+        for op in OPS {
+            check(&Expr::bin(op, a(), b()), |builder|builder, |x,y|apply(op, x, y));
+            check(&Expr::bin(op, a()^0x1234u64, 7u64), |builder|builder, |x,_|apply(op, x^0x1234, 7));
+        }
+    }
+
+    #[test]
+    fn select_with_zero_else(){
+        check(&Expr::select(a().lt(b()), a()^b(), 0), |builder|builder, |x,y| if (x as i64)<(y as i64) { x^y } else { 0 });
+        // a condition that is not a comparison is tested against zero
+        check(&Expr::select(a()&1u64, b()+5u64, 0), |builder|builder, |x,y| if x&1!=0 { y.wrapping_add(5) } else { 0 });
+    }
+
+    #[test]
+    fn select_with_else(){
+        check(&Expr::select(a().ge(b()), a()-b(), b()*3u64), |builder|builder, |x,y| if (x as i64)>=(y as i64) { x.wrapping_sub(y) } else { y.wrapping_mul(3) });
+        check(&Expr::select(a()>>63u64, !b(), -a()), |builder|builder, |x,y| if x>>63!=0 { !y } else { x.wrapping_neg() });
+    }
+
+    #[test]
+    fn deep_variables(){
+        // x sits under y and z, so reading it takes DW_OP_pick 2 and up
+        let expr=Expr::bind(a(), |x| Expr::bind(b(), |y| Expr::bind(x.clone()^y.clone(), |z| (x-y)*z.clone()+z)));
+        check(&expr, |builder|builder, |x,y|x.wrapping_sub(y).wrapping_mul(x^y).wrapping_add(x^y));
+    }
+
+    #[test]
+    fn flattened_chain(){
+        let expr=Expr::select(a().eq(0u64), 10, Expr::select(a().lt(b()), a()+b(), Expr::select(b()&1u64, a()^b(), a()-b())));
+        let expected=|x:u64,y:u64| match (x,y){
+            (0,_)=>10,
+            _ if (x as i64)<(y as i64)=>x.wrapping_add(y),
+            _ if y&1!=0=>x^y,
+            _=>x.wrapping_sub(y),
+        };
+        check(&expr, |builder|builder.branches(), expected);
+        let mut rng=ChaCha8Rng::seed_from_u64(0);
+        assert!(has_branch(&bytes(&ExpressionBuilder::new(&mut rng, 2).branches().build(&expr))));
+    }
+
+    #[test]
+    fn masked_chain_fallback(){
+        // literals this deep put the end of the chain out of DW_OP_skip's reach
+        let expr=Expr::select(a().lt(b()), a()+1u64+2u64+3u64, b()^4u64);
+        for seed in 0..4 {
+            let mut rng=ChaCha8Rng::seed_from_u64(seed);
+            let bytecode=bytes(&ExpressionBuilder::new(&mut rng, 12).branches().build(&expr));
+            assert!(bytecode.len()>1<<15);
+            assert!(!has_branch(&bytecode));
+            for _ in 0..8 {
+                let (x,y)=(value(&mut rng),value(&mut rng));
+                let expected=if (x as i64)<(y as i64) { x.wrapping_add(6) } else { y^4 };
+                assert_eq!(run(&bytecode, x, y, rng.gen()), expected);
+            }
+        }
+    }
+
+    // what a tree computes from the two registers
+    type Value=fn(u64,u64)->u64;
+
+    fn trees()->Vec<(Expr,Value)>{
+        vec![
+            (a()^0x0123_4567_89ab_cdefu64,|x,_|x^0x0123_4567_89ab_cdef),
+            (Expr::select(a().eq(b()), 0x55u64, a()+0x1000u64),|x,y| if x==y { 0x55 } else { x.wrapping_add(0x1000) }),
+            ((b()*0x9e37_79b9u64)-(a()|0xff00u64),|x,y|y.wrapping_mul(0x9e37_79b9).wrapping_sub(x|0xff00)),
+        ]
+    }
+
+    #[test]
+    fn per_rule_budget(){
+        let (exprs,expected):(Vec<Expr>,Vec<Value>)=trees().into_iter().unzip();
+        for seed in 0..4 {
+            let mut rng=ChaCha8Rng::seed_from_u64(seed);
+            let built=ExpressionBuilder::with_budget(&mut rng, Budget::PerRule(3000)).opaque(vec![A,B]).build_all(&exprs);
+            for (exp,expected) in built.iter().zip(&expected) {
+                let bytecode=bytes(exp);
+                // with so few literals one level more or less is a big step
+                assert!((2400..=3600).contains(&bytecode.len()), "seed {}: {} bytes", seed, bytecode.len());
+                let (x,y)=(value(&mut rng),value(&mut rng));
+                assert_eq!(run(&bytecode, x, y, rng.gen()), expected(x, y));
+            }
+        }
+    }
+
+    #[test]
+    fn total_budget(){
+        let (exprs,expected):(Vec<Expr>,Vec<Value>)=trees().into_iter().unzip();
+        for seed in 0..4 {
+            let mut rng=ChaCha8Rng::seed_from_u64(seed);
+            let built=ExpressionBuilder::with_budget(&mut rng, Budget::Total(8000)).build_all(&exprs);
+            let mut total=0;
+            for (exp,expected) in built.iter().zip(&expected) {
+                let bytecode=bytes(exp);
+                total+=bytecode.len();
+                let (x,y)=(value(&mut rng),value(&mut rng));
+                assert_eq!(run(&bytecode, x, y, rng.gen()), expected(x, y));
+            }
+            assert!((7200..=8800).contains(&total), "seed {}: {} bytes", seed, total);
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use std::fmt;
+
+use gimli::write::{
+    Address, CallFrameInstruction, CommonInformationEntry, EhFrame, EndianVec, Expression, FrameDescriptionEntry, FrameTable,
+    Result, Writer,
+};
+use gimli::{Encoding, Format, LittleEndian, Register, UnwindSection};
+
+/// The encoding the rules are written for unless configured otherwise. The
+/// format matches the 32-bit `.eh_frame` the rules end up in.
+pub const DEFAULT_ENCODING:Encoding=Encoding { address_size: 8, format: Format::Dwarf32, version: 5 };
+
+/// An encoding the unwinder reading the rules cannot handle.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum EncodingError{
+    AddressSize(u8),
+    Version(u16),
+    /// libgcc only walks `.eh_frame` sections with 32-bit lengths
+    Dwarf64EhFrame,
+}
+
+impl fmt::Display for EncodingError{
+    fn fmt(&self,f:&mut fmt::Formatter<'_>)->fmt::Result{
+        match self{
+            EncodingError::AddressSize(size)=>write!(f, "address size {} is not supported, the unwinder uses 8 byte addresses", size),
+            EncodingError::Version(version)=>write!(f, "DWARF version {} is not supported, val_expression needs version 3 to 5", version),
+            EncodingError::Dwarf64EhFrame=>write!(f, "64-bit DWARF cannot be used for a .eh_frame section"),
+        }
+    }
+}
+
+impl std::error::Error for EncodingError{}
+
+/// Rejects encodings the unwinder of a 64-bit host would misread: other
+/// address sizes, versions before `DW_CFA_val_expression` existed or newer
+/// than we know of, and 64-bit DWARF, since the rules always end up in a
+/// 32-bit `.eh_frame`, whether the assembler builds it from `.cfi_escape` or
+/// we write it into an object.
+///
+/// This only decides what is accepted: the rules use no operation whose
+/// operands depend on the encoding, and `.eh_frame` CIEs are always version
+/// 1, so every accepted encoding gives the same bytes.
+pub fn check_encoding(encoding:Encoding)->std::result::Result<(),EncodingError>{
+    if encoding.address_size!=8 {
+        return Err(EncodingError::AddressSize(encoding.address_size));
+    }
+    if !(3..=5).contains(&encoding.version) {
+        return Err(EncodingError::Version(encoding.version));
+    }
+    if encoding.format==Format::Dwarf64 {
+        return Err(EncodingError::Dwarf64EhFrame);
+    }
+    Ok(())
+}
+
+/// Returns the bytecode of `exp`.
+///
+/// gimli only serializes expressions as part of a section, so this writes a
+/// throwaway `.eh_frame` holding just `exp` and reads the bytes back out.
+pub fn expression_bytes(exp:&Expression,encoding:Encoding)->Result<Vec<u8>>{
+    // .eh_frame CIEs are always version 1
+    let encoding=Encoding { version: 1, ..encoding };
+    let cie=CommonInformationEntry::new(encoding, 1, 1, Register(0));
+    let mut fde=FrameDescriptionEntry::new(Address::Constant(0), 0);
+    fde.add_instruction(0, CallFrameInstruction::ValExpression(Register(0), exp.clone()));
+    let mut table=FrameTable::default();
+    let cie_id=table.add_cie(cie);
+    table.add_fde(cie_id, fde);
+    let mut eh_frame=EhFrame(EndianVec::new(LittleEndian));
+    table.write_eh_frame(&mut eh_frame)?;
+
+    let section=gimli::read::EhFrame::new(eh_frame.0.slice(), LittleEndian);
+    let bases=gimli::BaseAddresses::default();
+    let mut entries=section.entries(&bases);
+    while let Some(entry)=entries.next().expect("gimli wrote an unreadable .eh_frame") {
+        if let gimli::CieOrFde::Fde(partial)=entry {
+            let fde=partial.parse(|section,bases,offset| section.cie_from_offset(bases, offset))
+                .expect("gimli wrote an unreadable FDE");
+            let mut instructions=fde.instructions(&section, &bases);
+            while let Some(instruction)=instructions.next().expect("gimli wrote an unreadable CFA instruction") {
+                if let gimli::CallFrameInstruction::ValExpression { expression, .. }=instruction {
+                    return Ok(expression.0.slice().to_vec());
+                }
+            }
+        }
+    }
+    unreachable!("the val_expression rule was not written back");
+}
+
+/// Writes a single `DW_CFA_val_expression`: the opcode, the ULEB register
+/// and the length-prefixed expression, as `.cfi_escape` expects it.
+pub fn write_val_expression<W:Writer>(w:&mut W,register:Register,exp:&Expression,encoding:Encoding)->Result<()>{
+    let bytes=expression_bytes(exp, encoding)?;
+    w.write_u8(gimli::DW_CFA_val_expression.0)?;
+    w.write_uleb128(register.0.into())?;
+    w.write_uleb128(bytes.len() as u64)?;
+    w.write(&bytes)
+}
@@ -0,0 +1,274 @@
+use std::fmt;
+use std::str::FromStr;
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use crate::builder::Expr;
+
+/// A cipher over the two flag halves, run by the VM's round loop.
+///
+/// Each round the accumulator steps by `det` and becomes the round's
+/// counter, then handlers 1 and 2 apply one step each. A step updates both
+/// halves at once from their old values, the way the unwinder updates every
+/// register. [`step`](Self::step) is the reference the expected ciphertext is
+/// computed with and [`lower`](Self::lower) what the handlers run, so the two
+/// must agree.
+pub trait Cipher:fmt::Debug{
+    /// The halves after step `step`, 0 or 1, of the round with `counter`.
+    fn step(&self,step:usize,counter:u64,state:(u64,u64))->(u64,u64);
+
+    /// [`step`](Self::step) over expressions for the counter and the halves.
+    fn lower(&self,step:usize,counter:&Expr,a:&Expr,b:&Expr)->(Expr,Expr);
+
+    /// The keys, by name; none may appear verbatim in the rules.
+    fn secrets(&self)->Vec<(&'static str,u64)>;
+
+    /// Both steps of a round.
+    fn round(&self,counter:u64,state:(u64,u64))->(u64,u64){
+        self.step(1, counter, self.step(0, counter, state))
+    }
+}
+
+fn rol(x:u64,n:u32)->u64{
+    x.rotate_left(n)
+}
+
+fn rol_expr(x:&Expr,n:u32)->Expr{
+    (x.clone()<<u64::from(n))|(x.clone()>>u64::from(64-n))
+}
+
+/// How two values are combined.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Mix{
+    Xor,
+    Add,
+    Sub,
+}
+
+impl Mix{
+    pub const ALL:[Mix;3]=[Mix::Xor,Mix::Add,Mix::Sub];
+
+    pub fn apply(self,x:u64,y:u64)->u64{
+        match self{
+            Mix::Xor=>x^y,
+            Mix::Add=>x.wrapping_add(y),
+            Mix::Sub=>x.wrapping_sub(y),
+        }
+    }
+
+    pub fn lower(self,x:Expr,y:Expr)->Expr{
+        match self{
+            Mix::Xor=>x^y,
+            Mix::Add=>x+y,
+            Mix::Sub=>x-y,
+        }
+    }
+}
+
+/// The challenge's Feistel network, `a = a mix f(c,b)` and then a swap, with
+/// `f(c,b) = (c+key) mix (c >> s) mix (b << t)`.
+///
+/// Each mix, both amounts and whether the counter and the other half are
+/// shifted or rotated are drawn per challenge; the original used xor
+/// throughout and shifted by 30 and 24.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Feistel{
+    pub key:u64,
+    pub counter_shift:u32,
+    pub counter_rotate:bool,
+    pub half_shift:u32,
+    pub half_rotate:bool,
+    /// the mixes inside f, then the one applying f to the half
+    pub mix:[Mix;3],
+}
+
+impl Feistel{
+    /// A random key and round function.
+    pub fn sample<R:Rng+?Sized>(rng:&mut R)->Feistel{
+        Feistel {
+            key: rng.gen(),
+            counter_shift: rng.gen_range(1..64),
+            counter_rotate: rng.gen(),
+            half_shift: rng.gen_range(1..64),
+            half_rotate: rng.gen(),
+            mix: [(); 3].map(|_|*Mix::ALL.choose(rng).unwrap()),
+        }
+    }
+
+    fn f(&self,counter:u64,b:u64)->u64{
+        let counter_term=if self.counter_rotate { counter.rotate_right(self.counter_shift) } else { counter>>self.counter_shift };
+        let half_term=if self.half_rotate { rol(b, self.half_shift) } else { b<<self.half_shift };
+        self.mix[1].apply(self.mix[0].apply(counter.wrapping_add(self.key), counter_term), half_term)
+    }
+
+    fn f_expr(&self,counter:&Expr,b:&Expr)->Expr{
+        let counter_term=if self.counter_rotate {
+            rol_expr(counter, 64-self.counter_shift)
+        } else {
+            counter.clone()>>u64::from(self.counter_shift)
+        };
+        let half_term=if self.half_rotate { rol_expr(b, self.half_shift) } else { b.clone()<<u64::from(self.half_shift) };
+        self.mix[1].lower(self.mix[0].lower(counter.clone()+self.key, counter_term), half_term)
+    }
+}
+
+impl Cipher for Feistel{
+    fn step(&self,step:usize,counter:u64,(a,b):(u64,u64))->(u64,u64){
+        match step{
+            0=>(self.mix[2].apply(a, self.f(counter, b)),b),
+            _=>(b,a),
+        }
+    }
+
+    fn lower(&self,step:usize,counter:&Expr,a:&Expr,b:&Expr)->(Expr,Expr){
+        match step{
+            0=>(self.mix[2].lower(a.clone(), self.f_expr(counter, b)),b.clone()),
+            _=>(b.clone(),a.clone()),
+        }
+    }
+
+    fn secrets(&self)->Vec<(&'static str,u64)>{
+        vec![("hash_num",self.key)]
+    }
+}
+
+/// XTEA's round on 64-bit halves, one half per step:
+/// `a += ((b<<4 ^ b>>5) + b) ^ (c+k0)`, then the same into `b` with `k1`.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Xtea{
+    pub k0:u64,
+    pub k1:u64,
+}
+
+impl Xtea{
+    fn mix(x:u64,counter:u64,key:u64)->u64{
+        (((x<<4)^(x>>5)).wrapping_add(x))^counter.wrapping_add(key)
+    }
+
+    fn mix_expr(x:&Expr,counter:&Expr,key:u64)->Expr{
+        (((x.clone()<<4u64)^(x.clone()>>5u64))+x.clone())^(counter.clone()+key)
+    }
+}
+
+impl Cipher for Xtea{
+    fn step(&self,step:usize,counter:u64,(a,b):(u64,u64))->(u64,u64){
+        match step{
+            0=>(a.wrapping_add(Xtea::mix(b, counter, self.k0)),b),
+            _=>(a,b.wrapping_add(Xtea::mix(a, counter, self.k1))),
+        }
+    }
+
+    fn lower(&self,step:usize,counter:&Expr,a:&Expr,b:&Expr)->(Expr,Expr){
+        match step{
+            0=>(a.clone()+Xtea::mix_expr(b, counter, self.k0),b.clone()),
+            _=>(a.clone(),b.clone()+Xtea::mix_expr(a, counter, self.k1)),
+        }
+    }
+
+    fn secrets(&self)->Vec<(&'static str,u64)>{
+        vec![("xtea_k0",self.k0),("xtea_k1",self.k1)]
+    }
+}
+
+/// Speck's round: `a = (a ror 8) + b ^ (c^key)`, then `b = (b rol 3) ^ a`.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct Speck{
+    pub key:u64,
+}
+
+impl Cipher for Speck{
+    fn step(&self,step:usize,counter:u64,(a,b):(u64,u64))->(u64,u64){
+        match step{
+            0=>(rol(a, 64-8).wrapping_add(b)^(counter^self.key),b),
+            _=>(a,rol(b, 3)^a),
+        }
+    }
+
+    fn lower(&self,step:usize,counter:&Expr,a:&Expr,b:&Expr)->(Expr,Expr){
+        match step{
+            0=>((rol_expr(a, 64-8)+b.clone())^(counter.clone()^self.key),b.clone()),
+            _=>(a.clone(),rol_expr(b, 3)^a.clone()),
+        }
+    }
+
+    fn secrets(&self)->Vec<(&'static str,u64)>{
+        vec![("speck_key",self.key)]
+    }
+}
+
+/// Half of a SipRound on two words, keyed by the counter:
+/// `a, b = a+b, (b rol 13) ^ (a+b)`, then `a, b = (a rol 32) ^ (c+key), b + (a rol 17)`.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct SipRound{
+    pub key:u64,
+}
+
+impl Cipher for SipRound{
+    fn step(&self,step:usize,counter:u64,(a,b):(u64,u64))->(u64,u64){
+        match step{
+            0=>{
+                let sum=a.wrapping_add(b);
+                (sum,rol(b, 13)^sum)
+            }
+            _=>(rol(a, 32)^counter.wrapping_add(self.key),b.wrapping_add(rol(a, 17))),
+        }
+    }
+
+    fn lower(&self,step:usize,counter:&Expr,a:&Expr,b:&Expr)->(Expr,Expr){
+        match step{
+            0=>(a.clone()+b.clone(),rol_expr(b, 13)^(a.clone()+b.clone())),
+            _=>(rol_expr(a, 32)^(counter.clone()+self.key),b.clone()+rol_expr(a, 17)),
+        }
+    }
+
+    fn secrets(&self)->Vec<(&'static str,u64)>{
+        vec![("sip_key",self.key)]
+    }
+}
+
+/// The ciphers a challenge can be built with.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum CipherKind{
+    Feistel,
+    Xtea,
+    Speck,
+    SipRound,
+}
+
+impl CipherKind{
+    pub const ALL:[CipherKind;4]=[CipherKind::Feistel,CipherKind::Xtea,CipherKind::Speck,CipherKind::SipRound];
+
+    pub fn name(self)->&'static str{
+        match self{
+            CipherKind::Feistel=>"feistel",
+            CipherKind::Xtea=>"xtea",
+            CipherKind::Speck=>"speck",
+            CipherKind::SipRound=>"sipround",
+        }
+    }
+
+    /// An instance with random keys.
+    pub fn sample<R:Rng+?Sized>(self,rng:&mut R)->Box<dyn Cipher>{
+        match self{
+            CipherKind::Feistel=>Box::new(Feistel::sample(rng)),
+            CipherKind::Xtea=>Box::new(Xtea { k0: rng.gen(), k1: rng.gen() }),
+            CipherKind::Speck=>Box::new(Speck { key: rng.gen() }),
+            CipherKind::SipRound=>Box::new(SipRound { key: rng.gen() }),
+        }
+    }
+}
+
+impl fmt::Display for CipherKind{
+    fn fmt(&self,f:&mut fmt::Formatter<'_>)->fmt::Result{
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for CipherKind{
+    type Err=String;
+
+    fn from_str(s:&str)->Result<CipherKind,String>{
+        CipherKind::ALL.iter().copied().find(|kind|kind.name()==s).ok_or_else(|| format!("unknown cipher `{}`",s))
+    }
+}
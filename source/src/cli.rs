@@ -0,0 +1,203 @@
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+
+use dwraf_generator::arch::Arch;
+use dwraf_generator::cipher::CipherKind;
+use dwraf_generator::cfi::DEFAULT_ENCODING;
+use dwraf_generator::flag::FlagFormat;
+use dwraf_generator::{vm, Budget, DEFAULT_DEPTH};
+use gimli::Encoding;
+
+pub const USAGE:&str="\
+Usage: dwraf_generator [OPTIONS]
+
+Options:
+  -o, --output <PATH>     write the generated code to PATH instead of stdout
+      --flag-out <PATH>   append the flag to PATH instead of printing it to stderr
+      --header <PATH>     write the VM register macros the harness includes to PATH
+      --depth <N>         exp_constu obfuscation depth for every literal [default: 9]
+      --budget <BYTES>    size each rule to about BYTES, picking literal depths to fit
+      --total-budget <BYTES>
+                          like --budget, but for all rules together
+      --rounds <LO-HI>    range the cipher round count is drawn from, at most 1637
+                          [default: 16-32]
+      --words <N>         hex flag length in 64-bit words, even and at most 16; over
+                          2 the harness passes the flag in memory, which only the
+                          x86_64 harnesses cpp/t1_mem.cpp and cpp/t1_str.cpp do,
+                          so other archs and elf output are rejected [default: 2]
+      --flag-format <FORMAT>
+                          what goes between the flag's braces: hex (random words),
+                          printable:N (N random printable characters) or
+                          leet:PHRASE (PHRASE in random leet speak); text flags are
+                          checked whole, packed into words, and in memory like
+                          --words when over 16 bytes; they need cpp/t1_str.cpp,
+                          so elf output rejects them [default: hex]
+      --flag-prefix <PREFIX>
+                          text before the flag's braces [default: flag]
+      --program <PATH>    assemble the VM program the harness runs from PATH instead
+                          of using the built-in one, see vm::SOURCE
+      --cipher <CIPHER>   cipher the VM runs on the flag: feistel, xtea, speck or
+                          sipround [default: feistel]
+      --format <FORMAT>   output format: cfi (asm .cfi_escape line), raw (bytecode)
+                          or elf (relocatable object defining `op`) [default: cfi]
+      --mba <N>           rewrite xor, and, or and plus into mixed boolean-arithmetic
+                          forms N levels deep, 0 to keep them as they are [default: 1]
+      --no-opaque         only use exp_constu's arithmetic identities, no opaque
+                          constants or predicates built from registers and the CFA
+      --branches          lower selects to a dispatcher that jumps with DW_OP_bra and
+                          DW_OP_skip, with dead arms, instead of masking every arm
+      --junk <RATE>       follow each expression node with a neutral sequence like
+                          `dup; drop` with probability RATE, 0 to 1 [default: 0.1]
+      --seed <N>          seed the generator for a reproducible build
+      --arch <ARCH>       target architecture: x86_64, aarch64 or riscv64 [default: x86_64]
+      --dwarf-version <N> DWARF version the rules are checked against, 3 to 5; only
+                          decides what is accepted, the bytes are the same for all
+                          [default: 5]
+      --fixed-regs        keep the architecture's default VM registers instead of
+                          drawing a random assignment
+  -h, --help              print this help";
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Format{
+    Cfi,
+    Raw,
+    Elf,
+}
+
+#[derive(Debug)]
+pub struct Options{
+    pub output:Option<PathBuf>,
+    pub flag_out:Option<PathBuf>,
+    pub header:Option<PathBuf>,
+    /// the last of --depth, --budget and --total-budget wins
+    pub budget:Budget,
+    pub rounds:RangeInclusive<u64>,
+    pub words:usize,
+    pub flag_format:FlagFormat,
+    pub flag_prefix:String,
+    pub program:Option<PathBuf>,
+    pub cipher:CipherKind,
+    pub format:Format,
+    pub mba:u32,
+    pub no_opaque:bool,
+    pub branches:bool,
+    pub junk:f64,
+    pub seed:Option<u64>,
+    pub arch:Arch,
+    pub encoding:Encoding,
+    pub fixed_regs:bool,
+    pub help:bool,
+}
+
+impl Default for Options{
+    fn default()->Self{
+        Options {
+            output: None,
+            flag_out: None,
+            header: None,
+            budget: Budget::Depth(DEFAULT_DEPTH),
+            rounds: 16..=32,
+            words: 2,
+            flag_format: FlagFormat::Hex,
+            flag_prefix: "flag".to_string(),
+            program: None,
+            cipher: CipherKind::Feistel,
+            format: Format::Cfi,
+            mba: 1,
+            no_opaque: false,
+            branches: false,
+            junk: 0.1,
+            seed: None,
+            arch: Arch::X86_64,
+            encoding: DEFAULT_ENCODING,
+            fixed_regs: false,
+            help: false,
+        }
+    }
+}
+
+fn parse_rounds(value:&str)->Result<RangeInclusive<u64>,String>{
+    let (lo,hi)=value.split_once('-').unwrap_or((value,value));
+    let lo:u64=lo.parse().map_err(|_|format!("invalid round count `{}`",lo))?;
+    let hi:u64=hi.parse().map_err(|_|format!("invalid round count `{}`",hi))?;
+    if lo==0||lo>hi {
+        return Err(format!("invalid round range `{}`",value));
+    }
+    if hi>vm::MAX_ROUNDS {
+        return Err(format!("at most {} rounds can be verified, not {}",vm::MAX_ROUNDS,hi));
+    }
+    Ok(lo..=hi)
+}
+
+fn parse_bytes(value:&str)->Result<usize,String>{
+    value.parse().map_err(|_|format!("invalid byte count `{}`",value))
+}
+
+impl Options{
+    pub fn parse(args:impl IntoIterator<Item=String>)->Result<Options,String>{
+        let mut opts=Options::default();
+        let mut args=args.into_iter();
+        while let Some(arg)=args.next(){
+            let mut value=|| args.next().ok_or_else(|| format!("{} needs a value",arg));
+            match arg.as_str(){
+                "-o"|"--output"=>opts.output=Some(value()?.into()),
+                "--flag-out"=>opts.flag_out=Some(value()?.into()),
+                "--header"=>opts.header=Some(value()?.into()),
+                "--depth"=>{
+                    let depth=value()?;
+                    opts.budget=Budget::Depth(depth.parse().map_err(|_|format!("invalid depth `{}`",depth))?);
+                }
+                "--budget"=>opts.budget=Budget::PerRule(parse_bytes(&value()?)?),
+                "--total-budget"=>opts.budget=Budget::Total(parse_bytes(&value()?)?),
+                "--rounds"=>opts.rounds=parse_rounds(&value()?)?,
+                "--words"=>{
+                    let words=value()?;
+                    opts.words=words.parse().ok().filter(|&words|words>=2&&words%2==0&&words<=vm::MAX_WORDS)
+                        .ok_or_else(||format!("invalid word count `{}`",words))?;
+                }
+                "--flag-format"=>opts.flag_format=value()?.parse()?,
+                "--flag-prefix"=>{
+                    let prefix=value()?;
+                    if !prefix.chars().all(|c|c.is_ascii_graphic()&&c!='{'&&c!='}') {
+                        return Err(format!("invalid flag prefix `{}`",prefix));
+                    }
+                    opts.flag_prefix=prefix;
+                }
+                "--program"=>opts.program=Some(value()?.into()),
+                "--cipher"=>opts.cipher=value()?.parse()?,
+                "--format"=>{
+                    opts.format=match value()?.as_str(){
+                        "cfi"=>Format::Cfi,
+                        "raw"=>Format::Raw,
+                        "elf"=>Format::Elf,
+                        other=>return Err(format!("unknown format `{}`",other)),
+                    }
+                }
+                "--mba"=>{
+                    let level=value()?;
+                    opts.mba=level.parse().map_err(|_|format!("invalid MBA level `{}`",level))?;
+                }
+                "--no-opaque"=>opts.no_opaque=true,
+                "--branches"=>opts.branches=true,
+                "--junk"=>{
+                    let rate=value()?;
+                    opts.junk=rate.parse().ok().filter(|rate|(0.0..=1.0).contains(rate))
+                        .ok_or_else(||format!("invalid junk rate `{}`",rate))?;
+                }
+                "--seed"=>{
+                    let seed=value()?;
+                    opts.seed=Some(seed.parse().map_err(|_|format!("invalid seed `{}`",seed))?);
+                }
+                "--arch"=>opts.arch=value()?.parse()?,
+                "--dwarf-version"=>{
+                    let version=value()?;
+                    opts.encoding.version=version.parse().map_err(|_|format!("invalid DWARF version `{}`",version))?;
+                }
+                "--fixed-regs"=>opts.fixed_regs=true,
+                "-h"|"--help"=>opts.help=true,
+                _=>return Err(format!("unexpected argument `{}`",arg)),
+            }
+        }
+        Ok(opts)
+    }
+}
@@ -0,0 +1,167 @@
+use std::error::Error;
+
+use gimli::write::{
+    Address, CallFrameInstruction, CommonInformationEntry, EhFrame, EndianVec, FrameDescriptionEntry, FrameTable, Writer,
+};
+use gimli::{DwEhPe, Encoding, Format, LittleEndian, X86_64};
+use object::write::{Object, Relocation, SectionId, Symbol, SymbolId, SymbolSection};
+use object::{
+    elf, Architecture, BinaryFormat, Endianness, RelocationEncoding, RelocationKind, SectionFlags, SectionKind, SymbolFlags,
+    SymbolKind, SymbolScope,
+};
+
+use crate::cfi;
+
+/// The function whose unwind info carries the VM rules.
+pub const OP_SYMBOL:&str="op";
+/// Provided by the host harness; throws the exception that unwinds through `op`.
+pub const THROW_SYMBOL:&str="dwraf_throw";
+
+// op:
+//   sub  $8,%rsp
+//   call dwraf_throw
+//   add  $8,%rsp
+//   ret
+const OP_CODE:[u8;14]=[0x48,0x83,0xec,0x08, 0xe8,0,0,0,0, 0x48,0x83,0xc4,0x08, 0xc3];
+const CALL_OFFSET:u32=4;
+const CALL_RELOC_OFFSET:u64=5;
+const RETURN_OFFSET:u32=9;
+const RET_OFFSET:u32=13;
+
+const PCREL_SDATA4:DwEhPe=DwEhPe(gimli::DW_EH_PE_pcrel.0|gimli::DW_EH_PE_sdata4.0);
+
+struct EhReloc{
+    offset:usize,
+    symbol:usize,
+    addend:i64,
+}
+
+// an EndianVec that leaves pc-relative symbol references for the linker
+struct RelocWriter{
+    data:EndianVec<LittleEndian>,
+    relocs:Vec<EhReloc>,
+}
+
+impl Writer for RelocWriter{
+    type Endian=LittleEndian;
+
+    fn endian(&self)->Self::Endian{
+        self.data.endian()
+    }
+
+    fn len(&self)->usize{
+        self.data.len()
+    }
+
+    fn write(&mut self,bytes:&[u8])->gimli::write::Result<()>{
+        self.data.write(bytes)
+    }
+
+    fn write_at(&mut self,offset:usize,bytes:&[u8])->gimli::write::Result<()>{
+        self.data.write_at(offset, bytes)
+    }
+
+    fn write_eh_pointer(&mut self,address:Address,eh_pe:DwEhPe,size:u8)->gimli::write::Result<()>{
+        match address{
+            Address::Symbol { symbol, addend } if eh_pe==PCREL_SDATA4=>{
+                self.relocs.push(EhReloc { offset: self.len(), symbol, addend });
+                self.write_u32(0)
+            }
+            Address::Symbol { .. }=>Err(gimli::write::Error::UnsupportedPointerEncoding(eh_pe)),
+            Address::Constant(_)=>self.data.write_eh_pointer(address, eh_pe, size),
+        }
+    }
+}
+
+/// Builds the `.eh_frame` for `op`: the usual x86-64 entry rules plus the VM
+/// rules at the call into the thrower.
+fn eh_frame(rules:Vec<CallFrameInstruction>)->Result<RelocWriter,gimli::write::Error>{
+    // .eh_frame CIEs are always version 1
+    let encoding=Encoding { address_size: 8, format: Format::Dwarf32, version: 1 };
+    let mut cie=CommonInformationEntry::new(encoding, 1, -8, X86_64::RA);
+    cie.fde_address_encoding=PCREL_SDATA4;
+    cie.add_instruction(CallFrameInstruction::Cfa(X86_64::RSP, 8));
+    cie.add_instruction(CallFrameInstruction::Offset(X86_64::RA, -8));
+
+    let mut fde=FrameDescriptionEntry::new(Address::Symbol { symbol: 0, addend: 0 }, OP_CODE.len() as u32);
+    fde.add_instruction(CALL_OFFSET, CallFrameInstruction::CfaOffset(16));
+    fde.add_instruction(CALL_OFFSET, CallFrameInstruction::RememberState);
+    for rule in rules {
+        fde.add_instruction(CALL_OFFSET, rule);
+    }
+    fde.add_instruction(RETURN_OFFSET, CallFrameInstruction::RestoreState);
+    fde.add_instruction(RET_OFFSET, CallFrameInstruction::CfaOffset(8));
+
+    let mut table=FrameTable::default();
+    let cie_id=table.add_cie(cie);
+    table.add_fde(cie_id, fde);
+
+    let mut eh_frame=EhFrame(RelocWriter { data: EndianVec::new(LittleEndian), relocs: Vec::new() });
+    table.write_eh_frame(&mut eh_frame)?;
+    Ok(eh_frame.0)
+}
+
+/// Writes a relocatable x86-64 ELF object that defines `op` together with its
+/// unwind info, ready to be linked against a host harness. `encoding` is the
+/// one the rules were built for.
+pub fn write_object(rules:Vec<CallFrameInstruction>,encoding:Encoding)->Result<Vec<u8>,Box<dyn Error>>{
+    cfi::check_encoding(encoding)?;
+    let mut obj=Object::new(BinaryFormat::Elf, Architecture::X86_64, Endianness::Little);
+
+    let text=obj.section_id(object::write::StandardSection::Text);
+    let offset=obj.append_section_data(text, &OP_CODE, 16);
+    let op=obj.add_symbol(Symbol {
+        name: OP_SYMBOL.into(),
+        value: offset,
+        size: OP_CODE.len() as u64,
+        kind: SymbolKind::Text,
+        scope: SymbolScope::Linkage,
+        weak: false,
+        section: SymbolSection::Section(text),
+        flags: SymbolFlags::None,
+    });
+    let throw=obj.add_symbol(Symbol {
+        name: THROW_SYMBOL.into(),
+        value: 0,
+        size: 0,
+        kind: SymbolKind::Text,
+        scope: SymbolScope::Unknown,
+        weak: false,
+        section: SymbolSection::Undefined,
+        flags: SymbolFlags::None,
+    });
+    obj.add_relocation(text, Relocation {
+        offset: offset+CALL_RELOC_OFFSET,
+        size: 32,
+        kind: RelocationKind::PltRelative,
+        encoding: RelocationEncoding::X86Branch,
+        symbol: throw,
+        addend: -4,
+    })?;
+
+    let eh=eh_frame(rules)?;
+    let eh_section=add_section(&mut obj, ".eh_frame", elf::SHT_X86_64_UNWIND, elf::SHF_ALLOC.into());
+    obj.set_section_data(eh_section, eh.data.into_vec(), 8);
+    let symbols:[SymbolId;1]=[op];
+    for reloc in eh.relocs {
+        obj.add_relocation(eh_section, Relocation {
+            offset: reloc.offset as u64,
+            size: 32,
+            kind: RelocationKind::Relative,
+            encoding: RelocationEncoding::Generic,
+            symbol: symbols[reloc.symbol],
+            addend: reloc.addend,
+        })?;
+    }
+
+    // keep the stack non-executable when linked
+    add_section(&mut obj, ".note.GNU-stack", elf::SHT_PROGBITS, 0);
+
+    Ok(obj.write()?)
+}
+
+fn add_section(obj:&mut Object,name:&str,sh_type:u32,sh_flags:u64)->SectionId{
+    let id=obj.add_section(Vec::new(), name.into(), SectionKind::Elf(sh_type));
+    obj.section_mut(id).flags=SectionFlags::Elf { sh_flags };
+    id
+}
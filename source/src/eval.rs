@@ -0,0 +1,295 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use gimli::read::{EndianSlice, Operation};
+use gimli::{Encoding, LittleEndian, Register};
+
+/// Depth of the value stack in libgcc's `execute_stack_op`, counting the CFA
+/// it is seeded with.
+pub const STACK_LIMIT:usize=64;
+
+// runaway DW_OP_skip/DW_OP_bra loops
+const MAX_STEPS:usize=1<<24;
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum EvalError{
+    Parse(gimli::read::Error),
+    Unsupported(String),
+    StackUnderflow(usize),
+    StackOverflow(usize),
+    UnknownRegister(Register),
+    DivisionByZero(usize),
+    /// a `DW_OP_deref` of memory that is not there
+    BadAddress(u64),
+    BadBranch(usize),
+    TooManySteps,
+    EmptyStack,
+}
+
+impl fmt::Display for EvalError{
+    fn fmt(&self,f:&mut fmt::Formatter<'_>)->fmt::Result{
+        match self{
+            EvalError::Parse(err)=>write!(f, "malformed expression: {}", err),
+            EvalError::Unsupported(op)=>write!(f, "unsupported operation {}", op),
+            EvalError::StackUnderflow(pc)=>write!(f, "stack underflow at offset {}", pc),
+            EvalError::StackOverflow(pc)=>write!(f, "more than {} stack entries at offset {}", STACK_LIMIT, pc),
+            EvalError::UnknownRegister(register)=>write!(f, "read of unknown register {}", register.0),
+            EvalError::DivisionByZero(pc)=>write!(f, "division by zero at offset {}", pc),
+            EvalError::BadAddress(address)=>write!(f, "read of unmapped memory at {:#x}", address),
+            EvalError::BadBranch(pc)=>write!(f, "branch out of the expression at offset {}", pc),
+            EvalError::TooManySteps=>write!(f, "expression does not terminate"),
+            EvalError::EmptyStack=>write!(f, "expression leaves no value"),
+        }
+    }
+}
+
+impl std::error::Error for EvalError{}
+
+impl From<gimli::read::Error> for EvalError{
+    fn from(err:gimli::read::Error)->Self{
+        EvalError::Parse(err)
+    }
+}
+
+/// Register values an expression is evaluated against.
+pub type Registers=HashMap<Register,u64>;
+
+/// Memory an expression can read with `DW_OP_deref`, by byte address.
+pub type Memory=HashMap<u64,u8>;
+
+struct Stack{
+    values:Vec<u64>,
+    pc:usize,
+}
+
+impl Stack{
+    fn pop(&mut self)->Result<u64,EvalError>{
+        self.values.pop().ok_or(EvalError::StackUnderflow(self.pc))
+    }
+
+    fn push(&mut self,value:u64)->Result<(),EvalError>{
+        if self.values.len()>=STACK_LIMIT {
+            return Err(EvalError::StackOverflow(self.pc));
+        }
+        self.values.push(value);
+        Ok(())
+    }
+
+    fn binary(&mut self,f:impl FnOnce(u64,u64)->u64)->Result<(),EvalError>{
+        let first=self.pop()?;
+        let second=self.pop()?;
+        self.push(f(second,first))
+    }
+}
+
+/// Evaluates a `DW_CFA_val_expression` body the way libgcc's
+/// `execute_stack_op` does: the stack starts out holding the CFA, registers
+/// are read with `DW_OP_reg*`, comparisons are signed, and the result is
+/// whatever ends up on top. Loads are little-endian reads of `memory`.
+pub fn evaluate(bytecode:&[u8],encoding:Encoding,regs:&Registers,memory:&Memory,cfa:u64)->Result<u64,EvalError>{
+    let mut stack=Stack { values: vec![cfa], pc: 0 };
+    let read_reg=|register:Register| regs.get(&register).copied().ok_or(EvalError::UnknownRegister(register));
+    let mut steps=0;
+    while stack.pc<bytecode.len() {
+        steps+=1;
+        if steps>MAX_STEPS {
+            return Err(EvalError::TooManySteps);
+        }
+        let mut reader=EndianSlice::new(&bytecode[stack.pc..], LittleEndian);
+        let op=Operation::parse(&mut reader, encoding)?;
+        let next=bytecode.len()-reader.len();
+        let mut target=next;
+        match op{
+            Operation::Nop=>{}
+            Operation::Drop=>{
+                stack.pop()?;
+            }
+            Operation::Pick { index }=>{
+                let index=index as usize;
+                // DW_OP_dup and DW_OP_over parse as picks too, but only
+                // DW_OP_pick itself is kept off the bottom entry
+                let reach=stack.values.len()-(bytecode[stack.pc]==gimli::DW_OP_pick.0) as usize;
+                if index>=reach {
+                    return Err(EvalError::StackUnderflow(stack.pc));
+                }
+                let value=stack.values[stack.values.len()-1-index];
+                stack.push(value)?;
+            }
+            Operation::Swap=>{
+                let first=stack.pop()?;
+                let second=stack.pop()?;
+                stack.push(first)?;
+                stack.push(second)?;
+            }
+            Operation::Rot=>{
+                let first=stack.pop()?;
+                let second=stack.pop()?;
+                let third=stack.pop()?;
+                stack.push(first)?;
+                stack.push(third)?;
+                stack.push(second)?;
+            }
+            Operation::Abs=>{
+                let value=stack.pop()?;
+                stack.push((value as i64).wrapping_abs() as u64)?;
+            }
+            Operation::Neg=>{
+                let value=stack.pop()?;
+                stack.push(value.wrapping_neg())?;
+            }
+            Operation::Not=>{
+                let value=stack.pop()?;
+                stack.push(!value)?;
+            }
+            Operation::PlusConstant { value }=>{
+                let top=stack.pop()?;
+                stack.push(top.wrapping_add(value))?;
+            }
+            Operation::And=>stack.binary(|a,b|a&b)?,
+            Operation::Or=>stack.binary(|a,b|a|b)?,
+            Operation::Xor=>stack.binary(|a,b|a^b)?,
+            Operation::Plus=>stack.binary(|a,b|a.wrapping_add(b))?,
+            Operation::Minus=>stack.binary(|a,b|a.wrapping_sub(b))?,
+            Operation::Mul=>stack.binary(|a,b|a.wrapping_mul(b))?,
+            // shift counts wrap at 64 like the hardware shifts the C code compiles to
+            Operation::Shl=>stack.binary(|a,b|a.wrapping_shl(b as u32))?,
+            Operation::Shr=>stack.binary(|a,b|a.wrapping_shr(b as u32))?,
+            Operation::Shra=>stack.binary(|a,b|(a as i64).wrapping_shr(b as u32) as u64)?,
+            Operation::Div|Operation::Mod=>{
+                let first=stack.pop()?;
+                let second=stack.pop()?;
+                if first==0 {
+                    return Err(EvalError::DivisionByZero(stack.pc));
+                }
+                let value=match op{
+                    Operation::Div=>(second as i64).wrapping_div(first as i64) as u64,
+                    _=>second%first,
+                };
+                stack.push(value)?;
+            }
+            Operation::Eq=>stack.binary(|a,b|(a==b) as u64)?,
+            Operation::Ne=>stack.binary(|a,b|(a!=b) as u64)?,
+            Operation::Lt=>stack.binary(|a,b|((a as i64)<(b as i64)) as u64)?,
+            Operation::Le=>stack.binary(|a,b|((a as i64)<=(b as i64)) as u64)?,
+            Operation::Gt=>stack.binary(|a,b|((a as i64)>(b as i64)) as u64)?,
+            Operation::Ge=>stack.binary(|a,b|((a as i64)>=(b as i64)) as u64)?,
+            Operation::UnsignedConstant { value }=>stack.push(value)?,
+            Operation::SignedConstant { value }=>stack.push(value as u64)?,
+            Operation::Register { register }=>stack.push(read_reg(register)?)?,
+            Operation::RegisterOffset { register, offset, .. }=>{
+                stack.push(read_reg(register)?.wrapping_add(offset as u64))?;
+            }
+            Operation::Deref { size, space: false, .. }=>{
+                let address=stack.pop()?;
+                let mut value=0;
+                for k in (0..u64::from(size)).rev() {
+                    let byte=memory.get(&address.wrapping_add(k)).ok_or(EvalError::BadAddress(address.wrapping_add(k)))?;
+                    value=value<<8|u64::from(*byte);
+                }
+                stack.push(value)?;
+            }
+            Operation::Skip { target: offset }=>{
+                target=branch_target(next, offset, bytecode.len()).ok_or(EvalError::BadBranch(stack.pc))?;
+            }
+            Operation::Bra { target: offset }=>{
+                if stack.pop()?!=0 {
+                    target=branch_target(next, offset, bytecode.len()).ok_or(EvalError::BadBranch(stack.pc))?;
+                }
+            }
+            _=>return Err(EvalError::Unsupported(format!("{:?}",op))),
+        }
+        stack.pc=target;
+    }
+    stack.values.last().copied().ok_or(EvalError::EmptyStack)
+}
+
+pub(crate) fn branch_target(next:usize,offset:i16,len:usize)->Option<usize>{
+    let target=next.checked_add_signed(offset as isize)?;
+    (target<=len).then_some(target)
+}
+
+#[cfg(test)]
+mod tests{
+    use gimli::{
+        DW_OP_bra, DW_OP_const1s, DW_OP_const2u, DW_OP_deref, DW_OP_deref_size, DW_OP_drop, DW_OP_dup, DW_OP_ge, DW_OP_gt,
+        DW_OP_le, DW_OP_lit0, DW_OP_lit1, DW_OP_lit2, DW_OP_lit3, DW_OP_lit5, DW_OP_lt, DW_OP_over, DW_OP_pick, DW_OP_rot,
+        DW_OP_shr, DW_OP_shra, DW_OP_skip,
+    };
+
+    use super::*;
+    use crate::cfi::DEFAULT_ENCODING;
+
+    const CFA:u64=0xcfa;
+
+    fn run(bytecode:&[gimli::DwOp])->Result<u64,EvalError>{
+        let bytes:Vec<u8>=bytecode.iter().map(|op|op.0).collect();
+        run_bytes(&bytes, &Memory::new())
+    }
+
+    fn run_bytes(bytecode:&[u8],memory:&Memory)->Result<u64,EvalError>{
+        evaluate(bytecode, DEFAULT_ENCODING, &Registers::new(), memory, CFA)
+    }
+
+    #[test]
+    fn rot(){
+        // 1 2 3 -> 3 1 2: the top goes under the other two
+        assert_eq!(run(&[DW_OP_lit1,DW_OP_lit2,DW_OP_lit3,DW_OP_rot]), Ok(2));
+        assert_eq!(run(&[DW_OP_lit1,DW_OP_lit2,DW_OP_lit3,DW_OP_rot,DW_OP_drop]), Ok(1));
+        assert_eq!(run(&[DW_OP_lit1,DW_OP_lit2,DW_OP_lit3,DW_OP_rot,DW_OP_drop,DW_OP_drop]), Ok(3));
+    }
+
+    #[test]
+    fn pick_limit(){
+        // DW_OP_dup and DW_OP_over reach the CFA, DW_OP_pick does not
+        assert_eq!(run(&[DW_OP_dup]), Ok(CFA));
+        assert_eq!(run(&[DW_OP_lit5,DW_OP_over]), Ok(CFA));
+        assert_eq!(run_bytes(&[DW_OP_pick.0,0], &Memory::new()), Err(EvalError::StackUnderflow(0)));
+        assert_eq!(run_bytes(&[DW_OP_lit5.0,DW_OP_pick.0,1], &Memory::new()), Err(EvalError::StackUnderflow(1)));
+        assert_eq!(run_bytes(&[DW_OP_lit5.0,DW_OP_lit3.0,DW_OP_pick.0,1], &Memory::new()), Ok(5));
+    }
+
+    #[test]
+    fn shifts(){
+        // -16 >> 2, arithmetic and logical
+        assert_eq!(run_bytes(&[DW_OP_const1s.0,0xf0,DW_OP_lit2.0,DW_OP_shra.0], &Memory::new()), Ok(-4i64 as u64));
+        assert_eq!(run_bytes(&[DW_OP_const1s.0,0xf0,DW_OP_lit2.0,DW_OP_shr.0], &Memory::new()), Ok((-16i64 as u64)>>2));
+    }
+
+    #[test]
+    fn signed_comparisons(){
+        // -1 against 1
+        for (op,expected) in [(DW_OP_lt,1),(DW_OP_le,1),(DW_OP_gt,0),(DW_OP_ge,0)] {
+            assert_eq!(run_bytes(&[DW_OP_const1s.0,0xff,DW_OP_lit1.0,op.0], &Memory::new()), Ok(expected));
+        }
+    }
+
+    #[test]
+    fn deref_byte_order(){
+        let memory:Memory=(0..8).map(|k|(0x1000+k,k as u8+1)).collect();
+        assert_eq!(run_bytes(&[DW_OP_const2u.0,0x00,0x10,DW_OP_deref.0], &memory), Ok(0x0807_0605_0403_0201));
+        assert_eq!(run_bytes(&[DW_OP_const2u.0,0x02,0x10,DW_OP_deref_size.0,2], &memory), Ok(0x0403));
+        // the last byte of the word is not there
+        assert_eq!(run_bytes(&[DW_OP_const2u.0,0x01,0x10,DW_OP_deref.0], &memory), Err(EvalError::BadAddress(0x1008)));
+    }
+
+    #[test]
+    fn stack_limit(){
+        // the CFA and 63 literals fill the stack, the next one overflows it
+        let mut bytecode=vec![DW_OP_lit0;STACK_LIMIT-1];
+        assert_eq!(run(&bytecode), Ok(0));
+        bytecode.push(DW_OP_lit1);
+        assert_eq!(run(&bytecode), Err(EvalError::StackOverflow(STACK_LIMIT-1)));
+    }
+
+    #[test]
+    fn branch_targets(){
+        let memory=Memory::new();
+        // to the very end is fine, past it or before the start is not
+        assert_eq!(run_bytes(&[DW_OP_skip.0,1,0,DW_OP_lit1.0], &memory), Ok(CFA));
+        assert_eq!(run_bytes(&[DW_OP_skip.0,2,0,DW_OP_lit1.0], &memory), Err(EvalError::BadBranch(0)));
+        assert_eq!(run_bytes(&[DW_OP_lit1.0,DW_OP_bra.0,0xfb,0xff], &memory), Err(EvalError::BadBranch(1)));
+        // a branch not taken is never checked
+        assert_eq!(run_bytes(&[DW_OP_lit0.0,DW_OP_bra.0,0x00,0x10,DW_OP_lit5.0], &memory), Ok(5));
+        assert_eq!(run_bytes(&[DW_OP_lit1.0,DW_OP_bra.0,1,0,DW_OP_lit3.0,DW_OP_lit5.0], &memory), Ok(5));
+    }
+}
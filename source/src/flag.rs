@@ -0,0 +1,90 @@
+use std::fmt;
+use std::str::FromStr;
+
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use crate::vm::MAX_WORDS;
+
+/// The longest flag string, braces and prefix included, the VM can check.
+pub const MAX_LEN:usize=MAX_WORDS*8;
+
+// what leet speak may put in for a letter, besides the letter itself
+const LEET:[(char,&str);8]=[('a',"4@"),('b',"8"),('e',"3"),('g',"9"),('i',"1!"),('o',"0"),('s',"5$"),('t',"7")];
+
+/// What goes between the braces of a flag.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum FlagFormat{
+    /// random words, printed in hex
+    Hex,
+    /// this many random printable characters
+    Printable(usize),
+    /// a phrase with random leet-speak substitutions, spaces made underscores
+    Leet(String),
+}
+
+impl FlagFormat{
+    /// A random flag string, `prefix{...}`, or None for [`FlagFormat::Hex`],
+    /// whose flag is the words themselves.
+    pub fn sample<R:Rng+?Sized>(&self,rng:&mut R,prefix:&str)->Option<String>{
+        let body:String=match self{
+            FlagFormat::Hex=>return None,
+            // graphic ASCII without the braces, so the flag reads unambiguously
+            FlagFormat::Printable(len)=>(0..*len).map(|_| loop {
+                let c=rng.gen_range(b'!'..=b'~') as char;
+                if c!='{'&&c!='}' {
+                    break c;
+                }
+            }).collect(),
+            FlagFormat::Leet(phrase)=>phrase.chars().map(|c| {
+                let subs=LEET.iter().find(|(letter,_)|*letter==c.to_ascii_lowercase()).map_or("", |(_,subs)|*subs);
+                match c{
+                    ' '=>'_',
+                    _ if !subs.is_empty()&&rng.gen_bool(0.5)=>*subs.as_bytes().choose(rng).unwrap() as char,
+                    _ if rng.gen_bool(0.25)=>c.to_ascii_uppercase(),
+                    _=>c,
+                }
+            }).collect(),
+        };
+        Some(format!("{}{{{}}}",prefix,body))
+    }
+}
+
+impl fmt::Display for FlagFormat{
+    fn fmt(&self,f:&mut fmt::Formatter<'_>)->fmt::Result{
+        match self{
+            FlagFormat::Hex=>f.write_str("hex"),
+            FlagFormat::Printable(len)=>write!(f, "printable:{}", len),
+            FlagFormat::Leet(phrase)=>write!(f, "leet:{}", phrase),
+        }
+    }
+}
+
+impl FromStr for FlagFormat{
+    type Err=String;
+
+    fn from_str(s:&str)->Result<FlagFormat,String>{
+        match s.split_once(':'){
+            None if s=="hex"=>Ok(FlagFormat::Hex),
+            Some(("printable",len))=>len.parse().ok().filter(|len|(1..MAX_LEN).contains(len)).map(FlagFormat::Printable)
+                .ok_or_else(|| format!("invalid flag length `{}`",len)),
+            Some(("leet",phrase)) if !phrase.is_empty()&&phrase.chars().all(|c|c==' '||c.is_ascii_graphic()&&c!='{'&&c!='}')=>{
+                Ok(FlagFormat::Leet(phrase.to_string()))
+            }
+            Some(("leet",phrase))=>Err(format!("invalid leet phrase `{}`, use printable ASCII without braces",phrase)),
+            _=>Err(format!("unknown flag format `{}`",s)),
+        }
+    }
+}
+
+/// Packs a flag string into little-endian words, zero padded to an even
+/// count, the way a harness copies it into a zeroed word buffer.
+pub fn pack(flag:&str)->Result<Vec<u64>,String>{
+    if flag.len()>MAX_LEN {
+        return Err(format!("the flag is {} bytes, longer than the {} the VM can check",flag.len(),MAX_LEN));
+    }
+    let words=flag.len().div_ceil(16).max(1)*2;
+    let mut bytes=flag.as_bytes().to_vec();
+    bytes.resize(words*8, 0);
+    Ok(bytes.chunks(8).map(|word|u64::from_le_bytes(word.try_into().unwrap())).collect())
+}
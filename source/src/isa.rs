@@ -0,0 +1,162 @@
+use gimli::Register;
+
+use crate::arch::VmRegs;
+use crate::builder::Expr;
+
+/// A VM register, by what it holds.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Role{
+    Pc,
+    Acc,
+    FlagA,
+    FlagB,
+    Ptr,
+}
+
+impl Role{
+    pub const ALL:[Role;5]=[Role::Pc,Role::Acc,Role::FlagA,Role::FlagB,Role::Ptr];
+
+    pub fn register(self,regs:&VmRegs)->Register{
+        match self{
+            Role::Pc=>regs.pc,
+            Role::Acc=>regs.acc,
+            Role::FlagA=>regs.flag_a,
+            Role::FlagB=>regs.flag_b,
+            Role::Ptr=>regs.ptr,
+        }
+    }
+}
+
+/// An immediate following an opcode, which the handler reads from the pc.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Operand{
+    /// a signed byte, the jump distance from the opcode to an instruction
+    Target,
+    /// a little-endian integer of this many bytes
+    Int(usize),
+}
+
+impl Operand{
+    pub fn size(self)->usize{
+        match self{
+            Operand::Target=>1,
+            Operand::Int(size)=>size,
+        }
+    }
+}
+
+/// An opcode and the values its handler gives the registers, computed from
+/// the registers on entry.
+///
+/// A register it does not write keeps its value, except the pc, which
+/// becomes 0 and halts the program.
+#[derive(Debug,Clone)]
+pub struct Instruction{
+    pub opcode:u8,
+    pub name:&'static str,
+    pub operands:Vec<Operand>,
+    pub effects:Vec<(Role,Expr)>,
+}
+
+impl Instruction{
+    pub fn new(opcode:u8,name:&'static str)->Instruction{
+        Instruction { opcode, name, operands: Vec::new(), effects: Vec::new() }
+    }
+
+    /// Also takes `operand`, after the ones before it.
+    pub fn operand(mut self,operand:Operand)->Instruction{
+        self.operands.push(operand);
+        // the harness loads the pc with 8 bytes, the opcode first
+        assert!(self.size()<=8, "the operands of {} do not fit in the pc", self.name);
+        self
+    }
+
+    /// The opcode and its operands, in bytes.
+    pub fn size(&self)->usize{
+        1+self.operands.iter().map(|operand|operand.size()).sum::<usize>()
+    }
+
+    /// Also sets `role` to `value`.
+    pub fn write(mut self,role:Role,value:impl Into<Expr>)->Instruction{
+        assert!(self.effects.iter().all(|(written,_)|*written!=role), "{} writes {:?} twice", self.name, role);
+        self.effects.push((role,value.into()));
+        self
+    }
+}
+
+/// The VM's instruction set, which the handler rules are derived from.
+#[derive(Debug,Clone)]
+pub struct Isa{
+    instructions:Vec<Instruction>,
+}
+
+impl Isa{
+    pub fn new(instructions:Vec<Instruction>)->Isa{
+        for (i,instruction) in instructions.iter().enumerate() {
+            assert!(instructions[..i].iter().all(|other|other.opcode!=instruction.opcode), "opcode {} is defined twice", instruction.opcode);
+        }
+        Isa { instructions }
+    }
+
+    pub fn instructions(&self)->&[Instruction]{
+        &self.instructions
+    }
+
+    pub fn instruction(&self,name:&str)->Option<&Instruction>{
+        self.instructions.iter().find(|instruction|instruction.name==name)
+    }
+
+    /// The rule for every register some instruction writes: a select over the
+    /// opcode in the low byte of the pc, with an arm per distinct value.
+    ///
+    /// Opcodes giving a register the same value share an arm, tested a run of
+    /// consecutive opcodes at a time; `zero` stands in for the literal when
+    /// opcode 0 is tested on its own.
+    pub fn rules(&self,regs:&VmRegs,zero:&Expr)->Vec<(Register,Expr)>{
+        Role::ALL.iter().filter_map(|&role| {
+            let default=match role{
+                Role::Pc=>Expr::constant(0),
+                _=>Expr::reg(role.register(regs)),
+            };
+            // (value, opcodes) by the first opcode, leaving out what the default does anyway
+            let mut arms:Vec<(Expr,Vec<u8>)>=Vec::new();
+            let mut instructions:Vec<&Instruction>=self.instructions.iter().collect();
+            instructions.sort_by_key(|instruction|instruction.opcode);
+            for instruction in instructions {
+                for (_,value) in instruction.effects.iter().filter(|(written,value)|*written==role&&*value!=default) {
+                    match arms.iter_mut().find(|(other,_)|other==value) {
+                        Some((_,opcodes))=>opcodes.push(instruction.opcode),
+                        None=>arms.push((value.clone(),vec![instruction.opcode])),
+                    }
+                }
+            }
+            if arms.is_empty() {
+                return None;
+            }
+            let tree=Expr::bind(Expr::reg(regs.pc)&0xff, |op| {
+                arms.into_iter().rev().fold(default, |rest,(value,opcodes)| {
+                    Expr::select(opcode_test(&op, &opcodes, zero), value, rest)
+                })
+            });
+            Some((role.register(regs),tree))
+        }).collect()
+    }
+}
+
+// whether `op`, a byte, is one of `opcodes`, which are sorted
+fn opcode_test(op:&Expr,opcodes:&[u8],zero:&Expr)->Expr{
+    let mut runs:Vec<(u8,u8)>=Vec::new();
+    for &opcode in opcodes {
+        match runs.last_mut() {
+            Some((_,last)) if *last+1==opcode=>*last=opcode,
+            _=>runs.push((opcode,opcode)),
+        }
+    }
+    runs.into_iter().map(|run| match run{
+        (0,0)=>op.clone().eq(zero.clone()),
+        (first,last) if first==last=>op.clone().eq(u64::from(first)),
+        // the byte is never negative, so the signed compare is enough
+        (0,last)=>op.clone().le(u64::from(last)),
+        (first,last)=>op.clone().ge(u64::from(first))&op.clone().le(u64::from(last)),
+    }).reduce(|any,test|any|test).unwrap()
+}
@@ -0,0 +1,43 @@
+use gimli::write::Expression;
+use gimli::{
+    DW_OP_drop, DW_OP_dup, DW_OP_lit0, DW_OP_lit1, DW_OP_mul, DW_OP_neg, DW_OP_nop, DW_OP_not, DW_OP_or, DW_OP_over,
+    DW_OP_plus, DW_OP_rot, DW_OP_swap, DW_OP_xor,
+};
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use crate::eval::STACK_LIMIT;
+use crate::opaque::ops;
+
+/// Emits a random sequence that leaves the stack as it found it, given
+/// `height` values above the CFA, and returns its size in bytes.
+pub fn neutral<R:Rng+?Sized>(exp:&mut Expression,rng:&mut R,height:usize)->usize{
+    // the sequences that read the top need a value of ours there, and the
+    // ones that push one need room for it
+    let cases:u32=match height{
+        0=>1,
+        _ if height+2>STACK_LIMIT=>5,
+        _=>9,
+    };
+    match rng.gen_range(0..cases){
+        0=>ops(exp, &[DW_OP_nop]),
+        1=>ops(exp, &[DW_OP_not,DW_OP_not]),
+        2=>ops(exp, &[DW_OP_neg,DW_OP_neg]),
+        3=>ops(exp, &[DW_OP_swap,DW_OP_swap]),
+        4 if height>=2=>ops(exp, &[DW_OP_rot,DW_OP_rot,DW_OP_rot]),
+        4=>ops(exp, &[DW_OP_nop]),
+        5=>ops(exp, &[DW_OP_dup,DW_OP_drop]),
+        // libgcc's DW_OP_pick cannot reach the CFA at the bottom
+        6=>{
+            let index=rng.gen_range(0..height.min(256) as u32);
+            exp.op_pick(index as u8);
+            (if index<2 { 1 } else { 2 })+ops(exp, &[DW_OP_drop])
+        }
+        7=>ops(exp, &[DW_OP_over,DW_OP_drop]),
+        _=>{
+            let (identity,op)=*[(DW_OP_lit0,DW_OP_plus),(DW_OP_lit0,DW_OP_xor),(DW_OP_lit0,DW_OP_or),(DW_OP_lit1,DW_OP_mul)]
+                .choose(rng).unwrap();
+            ops(exp, &[identity,op])
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use std::fmt;
+
+use gimli::write::{EndianVec, Writer};
+use gimli::LittleEndian;
+
+/// Encodings shorter than this turn up in random bytes too often to mean anything.
+pub const MIN_LEAK_LEN:usize=4;
+
+/// A secret that appears verbatim in the bytecode.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct LeakError{
+    pub name:String,
+    pub encoding:&'static str,
+    pub offset:usize,
+}
+
+impl fmt::Display for LeakError{
+    fn fmt(&self,f:&mut fmt::Formatter<'_>)->fmt::Result{
+        write!(f, "{} appears as {} at offset {}", self.name, self.encoding, self.offset)
+    }
+}
+
+impl std::error::Error for LeakError{}
+
+/// The ways an operand can hold `value`: LEB128 as `DW_OP_constu`,
+/// `DW_OP_consts` and `DW_OP_plus_uconst` take it, and little-endian fixed
+/// width as `DW_OP_const4u`, `DW_OP_const4s` and `DW_OP_const8u` do.
+pub fn encodings(value:u64)->Vec<(&'static str,Vec<u8>)>{
+    let mut uleb=EndianVec::new(LittleEndian);
+    uleb.write_uleb128(value).unwrap();
+    let mut sleb=EndianVec::new(LittleEndian);
+    sleb.write_sleb128(value as i64).unwrap();
+    let mut encodings=vec![("ULEB128",uleb.into_vec()),("SLEB128",sleb.into_vec()),("u64",value.to_le_bytes().to_vec())];
+    if let Ok(value)=u32::try_from(value) {
+        encodings.push(("u32",value.to_le_bytes().to_vec()));
+    }
+    // a non-negative one already matches as u32
+    if let Ok(value@i32::MIN..=-1)=i32::try_from(value as i64) {
+        encodings.push(("i32",value.to_le_bytes().to_vec()));
+    }
+    // the zeros that pad a small value out to a fixed width are as common as any
+    encodings.retain(|(_,bytes)|bytes.iter().rposition(|&byte|byte!=0).map_or(0, |last|last+1)>=MIN_LEAK_LEN);
+    encodings
+}
+
+/// Fails on the first of `secrets`, named values, found in `bytecode` in
+/// any of its [`encodings`].
+pub fn check(bytecode:&[u8],secrets:&[(&str,u64)])->Result<(),LeakError>{
+    // one pass over the bytecode, trying only the patterns starting with the byte at hand
+    let mut by_first:Vec<Vec<(&str,&'static str,Vec<u8>)>>=vec![Vec::new();256];
+    for &(name,value) in secrets {
+        for (encoding,bytes) in encodings(value) {
+            by_first[bytes[0] as usize].push((name,encoding,bytes));
+        }
+    }
+    for (offset,&byte) in bytecode.iter().enumerate() {
+        for (name,encoding,bytes) in &by_first[byte as usize] {
+            if bytecode[offset..].starts_with(bytes) {
+                return Err(LeakError { name: name.to_string(), encoding, offset });
+            }
+        }
+    }
+    Ok(())
+}
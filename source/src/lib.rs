@@ -0,0 +1,19 @@
+pub mod arch;
+pub mod asm;
+pub mod builder;
+pub mod cfi;
+pub mod cipher;
+pub mod elf;
+pub mod eval;
+pub mod flag;
+pub mod isa;
+pub mod junk;
+pub mod leak;
+pub mod mba;
+pub mod obfuscate;
+pub mod opaque;
+pub mod stack;
+pub mod vm;
+
+pub use builder::{BinOp, Budget, Expr, ExpressionBuilder};
+pub use obfuscate::{exp_constu, exp_constu_from, DEFAULT_DEPTH};
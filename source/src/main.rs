@@ -1,443 +1,302 @@
-use std::mem::swap;
-
-use gimli::write::{Expression,CallFrameInstruction};
-use rand::{Rng, prelude::ThreadRng};
-
-fn exp_constu(exp:&mut Expression, rng:&mut ThreadRng,num:u64,depth:u64){
-    if depth==0 {
-        exp.op_constu(num);
-        return;
-    }
-    let ty:usize=rng.gen_range(0..9);
-    match ty{
-        0=>{
-            let xor_num:u64=rng.gen();
-            exp_constu(exp, rng, xor_num, depth-1);
-            exp_constu(exp, rng, xor_num^num, depth-1);
-            exp.op(gimli::DW_OP_xor);
-        }
-        1=>{
-            let add_num:u64=rng.gen();
-            exp_constu(exp, rng, add_num.wrapping_add(num), depth-1);
-            exp_constu(exp, rng, add_num, depth-1);
-            exp.op(gimli::DW_OP_minus);
-        }
-        2=>{
-            let add_num:u64=rng.gen();
-            exp_constu(exp, rng, num.wrapping_sub(add_num), depth-1);
-            exp_constu(exp, rng, add_num, depth-1);
-            exp.op(gimli::DW_OP_plus);
-        }
-        3=>{
-            let num1:u64=rng.gen::<u64>()&num;
-            let mut num2:u64=rng.gen::<u64>()&num;
-            num2|=num&!(num1|num2);
-            assert_eq!(num1|num2,num);
-            exp_constu(exp, rng, num1, depth-1);
-            exp_constu(exp, rng, num2, depth-1);
-            exp.op(gimli::DW_OP_or);
-        }
-        4=>{
-            let num1:u64=rng.gen::<u64>()&!num;
-            let mut num2:u64=rng.gen::<u64>()&!num;
-            num2|=!num&!(num1|num2);
-            assert_eq!(!num1&!num2,num);
-            exp_constu(exp, rng, !num1, depth-1);
-            exp_constu(exp, rng, !num2, depth-1);
-            exp.op(gimli::DW_OP_and);
-        }
-        5=>{
-            exp_constu(exp, rng, !num, depth-1);
-            exp.op(gimli::DW_OP_not);
-        }
-        6=>{
-            exp.op(gimli::DW_OP_dup);
-            exp_constu(exp, rng, num, depth-1);
-            exp.op(gimli::DW_OP_swap);
-            exp.op(gimli::DW_OP_drop);
-        }
-        7=>{
-            let rand_num:u64=rng.gen();
-            exp_constu(exp, rng, rand_num, depth-1);
-            exp_constu(exp, rng, num, depth-1);
-            exp.op(gimli::DW_OP_swap);
-            exp.op(gimli::DW_OP_drop);
-        }
-        8=>{
-            let rand_num1:u64=rng.gen();
-            let rand_num2:u64=rng.gen();
-            exp_constu(exp, rng, rand_num1, depth-1);
-            exp_constu(exp, rng, rand_num2, depth-1);
-            exp_constu(exp, rng, num, depth-1);
-            exp.op(gimli::DW_OP_rot);
-            exp.op(gimli::DW_OP_drop);
-            exp.op(gimli::DW_OP_drop);
-        }
-        _=>{}
-    }
-    
-}
-
-const DEFAULT_DEPTH:u64=9;
+mod cli;
+
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+
+use cli::{Format, Options};
+use dwraf_generator::arch::{Arch, VmRegs};
+use dwraf_generator::cipher::{Cipher, CipherKind};
+use dwraf_generator::flag::FlagFormat;
+use dwraf_generator::isa::{Instruction, Isa, Operand, Role};
+use dwraf_generator::{asm, cfi, elf, flag, leak, mba, stack, vm, Expr, ExpressionBuilder};
+use gimli::write::CallFrameInstruction;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+// rebuilds after the rules leak a secret before giving up
+const LEAK_RETRIES:usize=4;
 
 #[derive(Debug)]
 struct Arg{
-    flag_a:u64,
-    flag_b:u64,
+    /// two words per block, in the registers for one block and in memory for more
+    flag:Vec<u64>,
     det:u64,
     round:u64,
     xor_num_a:u64,
     xor_num_b:u64,
-    hash_num:u64,
+    cipher:Box<dyn Cipher>,
+}
+
+struct ArgSpec{
+    words:usize,
+    /// the flag words, random ones of `words` if None
+    flag:Option<Vec<u64>>,
+    rounds:RangeInclusive<u64>,
+    cipher:CipherKind,
+}
+
+impl Default for ArgSpec{
+    fn default()->Self{
+        ArgSpec { words: 2, flag: None, rounds: 16..=32, cipher: CipherKind::Feistel }
+    }
 }
 
 impl rand::prelude::Distribution<Arg> for rand::distributions::Standard {
     fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Arg {
-        let flag_a:u64=rng.gen();
-        let flag_b:u64=rng.gen();
+        ArgSpec::default().sample(rng)
+    }
+}
+
+impl rand::prelude::Distribution<Arg> for ArgSpec {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Arg {
+        let flag:Vec<u64>=match &self.flag {
+            Some(flag)=>flag.clone(),
+            None=>(0..self.words).map(|_|rng.gen()).collect(),
+        };
         let det:u64=rng.gen();
-        let round:u64=rng.gen_range(16..=32);
+        let round:u64=rng.gen_range(self.rounds.clone());
         let xor_num_a:u64=rng.gen();
         let xor_num_b:u64=rng.gen();
-        let hash_num:u64=rng.gen();
-        Arg { flag_a, flag_b, det, round, xor_num_a, xor_num_b, hash_num }
+        let cipher=self.cipher.sample(rng);
+        Arg { flag, det, round, xor_num_a, xor_num_b, cipher }
     }
 }
 
 impl Arg {
-    fn enc(&self)->(u64,u64){
-        let mut det=0;
-        let mut r14=self.flag_a;
-        let mut r15=self.flag_b;
-
-        r14^=self.xor_num_a;
-        r15^=self.xor_num_b;
-        while det!=self.round.wrapping_mul(self.det) {
-            det=det.wrapping_add(self.det);
-            r14^=(det.wrapping_add(self.hash_num))^(det>>30)^(r15<<24);
-            swap(&mut r14, &mut r15);
-        }
-        r14^=self.xor_num_a;
-        r15^=self.xor_num_b;
-        (r14,r15)
+    /// Whether the flag is read from memory rather than the flag registers.
+    fn in_memory(&self)->bool{
+        self.flag.len()>2
     }
 
-    fn generate_code(&self,rng:&mut ThreadRng)->Vec<u8>{
-        let mut w = gimli::write::EndianVec::new(gimli::LittleEndian);
-        let (ans_a,ans_b)=self.enc();
-        // r13 += det                                           0
-        // r14 ^= f(r13,r15)                                    1
-        // f(r13,r15) = (r13+hash_num)^(r13>>30)^(r15<<24)
-        // r14,r15 = r15,r14                                    2
-        // r14 ^= xor_num_a  r15^= xor_num_b                    3
-        // r12 = r12 >> 8                                       4
-        // r12 = 0                                              5
-        // r12 = r13 == (det*round) ? (r12 >> 8) : 2            6
-        // r13 = (r14!=ans_a | r15!=ans_b)                      7
-
-        // r12
-        {
-            // r12 = ((r12&0xff)==0)-1)
-            let mut exp=Expression::new();
-            exp.op_reg(gimli::X86_64::R12);
-            exp_constu(&mut exp, rng, 0xff, DEFAULT_DEPTH);
-            exp.op(gimli::DW_OP_and);
-            // <=3
-            exp.op(gimli::DW_OP_dup);
-            exp_constu(&mut exp, rng, 3, DEFAULT_DEPTH);
-            exp.op(gimli::DW_OP_le);
-            exp.op(gimli::DW_OP_swap);
-
-            // <=3 | ==4
-            exp.op(gimli::DW_OP_dup);
-            // exp_constu(&mut exp, rng, 4, DEFAULT_DEPTH);
-            {
-                exp_constu(&mut exp, rng, 1, DEFAULT_DEPTH);
-                exp.op(gimli::DW_OP_dup);
-                exp.op(gimli::DW_OP_dup);
-                exp.op(gimli::DW_OP_shl);
-                exp.op(gimli::DW_OP_shl);
-            }
-            exp.op(gimli::DW_OP_ne);
-            exp_constu(&mut exp, rng, 1, DEFAULT_DEPTH);
-            exp.op(gimli::DW_OP_minus);
-            exp.op_reg(gimli::X86_64::R12);
-            // exp_constu(&mut exp, rng, 8, DEFAULT_DEPTH);
-            {
-                exp_constu(&mut exp, rng, 1, DEFAULT_DEPTH);
-                exp.op(gimli::DW_OP_dup);
-                exp.op(gimli::DW_OP_dup);
-                exp.op(gimli::DW_OP_dup);
-                exp.op(gimli::DW_OP_shl);
-                exp.op(gimli::DW_OP_or);
-                exp.op(gimli::DW_OP_shl);
+    /// The expected ciphertext; every two-word block is encrypted on its own.
+    fn enc(&self)->Vec<u64>{
+        self.flag.chunks(2).flat_map(|block| {
+            let mut det=0;
+            let mut state=(block[0]^self.xor_num_a,block[1]^self.xor_num_b);
+            while det!=self.round.wrapping_mul(self.det) {
+                det=det.wrapping_add(self.det);
+                state=self.cipher.round(det, state);
             }
-            exp.op(gimli::DW_OP_shr);
-            exp_constu(&mut exp, rng, 64-8, DEFAULT_DEPTH);
-            exp.op(gimli::DW_OP_shl);
-            exp_constu(&mut exp, rng, 64-8, DEFAULT_DEPTH);
-            exp.op(gimli::DW_OP_shra);
-            exp.op(gimli::DW_OP_and);
-            exp.op(gimli::DW_OP_swap);
+            [state.0^self.xor_num_a,state.1^self.xor_num_b]
+        }).collect()
+    }
 
-            // <=3 | ==4 | ==6
-            exp_constu(&mut exp, rng, 6, DEFAULT_DEPTH);
-            exp.op(gimli::DW_OP_ne);
-            exp_constu(&mut exp, rng, 1, DEFAULT_DEPTH);
-            exp.op(gimli::DW_OP_minus);         // eq 6
-            exp.op_reg(gimli::X86_64::R13);
-            exp_constu(&mut exp, rng, self.round.wrapping_mul(self.det), DEFAULT_DEPTH);
-            exp.op(gimli::DW_OP_ne);
-            exp_constu(&mut exp, rng, 1, DEFAULT_DEPTH);
-            exp.op(gimli::DW_OP_minus);         // eq 6 | r13 == (det*round)
-            exp.op(gimli::DW_OP_dup);
-            exp.op(gimli::DW_OP_not);           // eq 6 | r13 == (det*round) | r13 != (det*round)
-            exp_constu(&mut exp, rng, 2, DEFAULT_DEPTH);
-            exp.op(gimli::DW_OP_and);
-            exp.op(gimli::DW_OP_swap);          // eq 6 | r13 != (det*round)?2:0 | r13 == (det*round)
-            exp.op_reg(gimli::X86_64::R12);
-            exp_constu(&mut exp, rng, 8, DEFAULT_DEPTH);
-            exp.op(gimli::DW_OP_shr);
-            exp_constu(&mut exp, rng, 64-8, DEFAULT_DEPTH);
-            exp.op(gimli::DW_OP_shl);
-            exp_constu(&mut exp, rng, 64-8, DEFAULT_DEPTH);
-            exp.op(gimli::DW_OP_shra);
-            exp.op(gimli::DW_OP_and);           // eq 6 | r13 != (det*round)?2:0 | r13 == (det*round)?next:0
-            exp.op(gimli::DW_OP_or);
-            exp.op(gimli::DW_OP_and);
+    // every value the rules must only ever compute, never hold
+    fn secrets(&self)->Vec<(String,u64)>{
+        let mut secrets=vec![
+            ("det".to_string(),self.det),
+            ("xor_num_a".to_string(),self.xor_num_a),
+            ("xor_num_b".to_string(),self.xor_num_b),
+            ("round*det".to_string(),self.round.wrapping_mul(self.det)),
+        ];
+        secrets.extend(self.flag.iter().enumerate().map(|(i,&word)|(format!("flag[{}]",i),word)));
+        secrets.extend(self.enc().into_iter().enumerate().map(|(i,word)|(format!("ans[{}]",i),word)));
+        secrets.extend(self.cipher.secrets().into_iter().map(|(name,value)|(name.to_string(),value)));
+        secrets
+    }
 
-            exp.op(gimli::DW_OP_or);
-            exp.op(gimli::DW_OP_or);
-            let cf=CallFrameInstruction::ValExpression(gimli::X86_64::R12, exp);
-            cf.simple_write(&mut w,gimli::Encoding { address_size: 8, format: gimli::Format::Dwarf64, version: 5 }).unwrap();
+    /// The VM's instructions; opcodes 8 to 10 only exist for a flag in
+    /// memory, which the pointer register walks, and 7 only for one in the
+    /// flag registers.
+    fn isa(&self,regs:&VmRegs)->Isa{
+        let ans=self.enc();
+        let pc=||Expr::reg(regs.pc);
+        let acc=||Expr::reg(regs.acc);
+        let a=||Expr::reg(regs.flag_a);
+        let b=||Expr::reg(regs.flag_b);
+        let ptr=||Expr::reg(regs.ptr);
+        // the byte after the opcode, sign extended
+        let next=||((pc()>>8)<<(64-8)).shra(64-8);
+        // the pointer's block, its low bit holding whether an earlier block failed
+        let block=||ptr()&!7u64;
+        let (step0_a,step0_b)=self.cipher.lower(0, &acc(), &a(), &b());
+        let (step1_a,step1_b)=self.cipher.lower(1, &acc(), &a(), &b());
+
+        let mut instructions=vec![
+            Instruction::new(0, "count").write(Role::Acc, acc()+self.det).write(Role::Pc, 1),
+            Instruction::new(1, "step0").write(Role::FlagA, step0_a).write(Role::FlagB, step0_b).write(Role::Pc, 1),
+            Instruction::new(2, "step1").write(Role::FlagA, step1_a).write(Role::FlagB, step1_b).write(Role::Pc, 1),
+            Instruction::new(3, "whiten").write(Role::FlagA, a()^self.xor_num_a).write(Role::FlagB, b()^self.xor_num_b).write(Role::Pc, 1),
+            Instruction::new(4, "jump").operand(Operand::Target).write(Role::Pc, next()),
+            Instruction::new(5, "halt"),
+            // to the target once the rounds are done, else on past it
+            Instruction::new(6, "loop").operand(Operand::Target).write(Role::Pc, Expr::select(acc().eq(self.round.wrapping_mul(self.det)), next(), 2)),
+        ];
+        if self.in_memory() {
+            let blocks:Vec<Expr>=ans.chunks(2).map(|expected|a().ne(expected[0])|b().ne(expected[1])).collect();
+            // the immediate zero extended, so every block has an index
+            let mismatch=Expr::bind((pc()>>8)&0xff, |k| Expr::select(k.clone().lt(blocks.len() as u64), block_test(&k, &blocks, 0), 1));
+            instructions.extend([
+                Instruction::new(8, "load").write(Role::FlagA, Expr::deref(block())).write(Role::FlagB, Expr::deref(block()+8u64))
+                    .write(Role::Acc, 0).write(Role::Pc, 1),
+                // checks the block against the immediate's block of the answer
+                Instruction::new(9, "next").operand(Operand::Int(1)).write(Role::Ptr, (ptr()|mismatch)+16u64).write(Role::Pc, 2),
+                Instruction::new(10, "end").write(Role::Acc, ptr()&1u64),
+            ]);
+        } else {
+            instructions.push(Instruction::new(7, "check").write(Role::Acc, a().ne(ans[0])|b().ne(ans[1])));
         }
+        Isa::new(instructions)
+    }
 
-        // r15
-        {
-            let mut exp=Expression::new();
-            exp.op_reg(gimli::X86_64::R12);
-            exp_constu(&mut exp, rng, 0xff, DEFAULT_DEPTH);
-            exp.op(gimli::DW_OP_and);
-            exp.op(gimli::DW_OP_dup);
-            exp_constu(&mut exp, rng, 2, DEFAULT_DEPTH);
-            exp.op(gimli::DW_OP_ne);
-            exp_constu(&mut exp, rng, 1, DEFAULT_DEPTH);
-            exp.op(gimli::DW_OP_minus);
-            exp.op(gimli::DW_OP_swap);
-            exp_constu(&mut exp, rng, 3, DEFAULT_DEPTH);
-            exp.op(gimli::DW_OP_ne);
-            exp_constu(&mut exp, rng, 1, DEFAULT_DEPTH);
-            exp.op(gimli::DW_OP_minus);
-            exp.op(gimli::DW_OP_dup);
-            exp.op_pick(2);
-            exp.op(gimli::DW_OP_or);
-            exp.op(gimli::DW_OP_not);
-
-            // is 2 | is 3 | else $
-            exp.op_reg(gimli::X86_64::R15);
-            exp.op(gimli::DW_OP_and);
-            exp.op(gimli::DW_OP_rot);
-
-            // else | is 2 | is 3 $
-            exp.op_reg(gimli::X86_64::R15);
-            exp_constu(&mut exp, rng, self.xor_num_b, DEFAULT_DEPTH);
-            exp.op(gimli::DW_OP_xor);
-            exp.op(gimli::DW_OP_and);
-            exp.op(gimli::DW_OP_rot);
-
-            // is 3 | else | is 2 $
-            exp.op_reg(gimli::X86_64::R14);
-            exp.op(gimli::DW_OP_and);
-
-            exp.op(gimli::DW_OP_or);
-            exp.op(gimli::DW_OP_or);
-
-            let cf=CallFrameInstruction::ValExpression(gimli::X86_64::R15, exp);
-
-            cf.simple_write(&mut w,gimli::Encoding { address_size: 8, format: gimli::Format::Dwarf64, version: 5 }).unwrap();
+    fn generate_code<R:Rng+?Sized>(&self,rng:&mut R,opts:&Options,regs:&VmRegs)->Vec<CallFrameInstruction>{
+        // x & ~x, whatever the opaque register holds
+        let zero=Expr::reg(regs.opaque_a)&!Expr::reg(regs.opaque_a);
+        let mut trees=self.isa(regs).rules(regs, &zero);
+
+        // the rules all read the registers as they were before the unwind, so
+        // any order works
+        trees.shuffle(rng);
+        // built together so a total budget is shared out over all of them
+        let (registers,trees):(Vec<_>,Vec<_>)=trees.into_iter().unzip();
+        let trees:Vec<Expr>=trees.iter().map(|tree|mba::rewrite(tree, rng, opts.mba)).collect();
+        let mut builder=ExpressionBuilder::with_budget(rng, opts.budget).junk(opts.junk);
+        if !opts.no_opaque {
+            // every VM register is live and readable when op unwinds
+            builder=builder.opaque(regs.registers().to_vec());
         }
-
-        // r13
-        {
-            let mut exp=Expression::new();
-            exp.op_reg(gimli::X86_64::R12);
-            exp_constu(&mut exp, rng, 0xff, DEFAULT_DEPTH);
-            exp.op(gimli::DW_OP_and);
-            
-            // ==0
-            exp.op(gimli::DW_OP_dup);
-            exp_constu(&mut exp, rng, 0, DEFAULT_DEPTH);
-            exp.op(gimli::DW_OP_ne);
-            exp_constu(&mut exp, rng, 1, DEFAULT_DEPTH);
-            exp.op(gimli::DW_OP_minus);
-            exp_constu(&mut exp, rng, self.det, DEFAULT_DEPTH);
-            exp.op_reg(gimli::X86_64::R13);
-            exp.op(gimli::DW_OP_plus);
-            exp.op(gimli::DW_OP_and);
-            exp.op(gimli::DW_OP_swap);
-
-            // ==0 | ==7
-            exp.op(gimli::DW_OP_dup);
-            // exp_constu(&mut exp, rng, 7, DEFAULT_DEPTH);
-            {
-                exp_constu(&mut exp, rng, 0, DEFAULT_DEPTH);
-                exp.op(gimli::DW_OP_not);
-                exp_constu(&mut exp, rng, 64-3, DEFAULT_DEPTH);
-                exp.op(gimli::DW_OP_shr);
-            }
-            exp.op(gimli::DW_OP_eq);
-            {
-                exp.op_reg(gimli::X86_64::R14);
-                exp_constu(&mut exp, rng, ans_a, DEFAULT_DEPTH);
-                exp.op(gimli::DW_OP_ne);
-                exp.op_reg(gimli::X86_64::R15);
-                exp_constu(&mut exp, rng, ans_b, DEFAULT_DEPTH);
-                exp.op(gimli::DW_OP_ne);
-                exp.op(gimli::DW_OP_or);
-            }
-            exp.op(gimli::DW_OP_and);
-            exp.op(gimli::DW_OP_swap);
-
-            // ==0 | ==7 | else
-            exp.op(gimli::DW_OP_dup);
-            // exp_constu(&mut exp, rng, 0, DEFAULT_DEPTH);
-            {
-                exp.op_reg(gimli::X86_64::RAX);
-                exp.op_reg(gimli::X86_64::RBX);
-                exp.op(gimli::DW_OP_dup);
-                exp.op(gimli::DW_OP_not);
-                exp.op(gimli::DW_OP_and);
-                exp.op(gimli::DW_OP_and);
-            }
-            exp.op(gimli::DW_OP_eq);
-            exp.op(gimli::DW_OP_swap);
-            exp_constu(&mut exp, rng, 7, DEFAULT_DEPTH);
-            exp.op(gimli::DW_OP_eq);
-            exp.op(gimli::DW_OP_or);
-            exp_constu(&mut exp, rng, 1, DEFAULT_DEPTH);
-            exp.op(gimli::DW_OP_minus);
-            exp.op_reg(gimli::X86_64::R13);
-
-
-            exp.op(gimli::DW_OP_and);
-
-
-            exp.op(gimli::DW_OP_or);
-            exp.op(gimli::DW_OP_or);
-
-            let cf=CallFrameInstruction::ValExpression(gimli::X86_64::R13, exp);
-            cf.simple_write(&mut w,gimli::Encoding { address_size: 8, format: gimli::Format::Dwarf64, version: 5 }).unwrap();
+        if opts.branches {
+            builder=builder.branches();
         }
+        builder.build_all(&trees).into_iter().zip(registers)
+            .map(|(exp,register)|CallFrameInstruction::ValExpression(register, exp))
+            .collect()
+    }
+}
 
-        // r14
-        {
-            let mut exp=Expression::new();
-            exp.op_reg(gimli::X86_64::R12);
-            exp_constu(&mut exp, rng, 0xff, DEFAULT_DEPTH);
-            exp.op(gimli::DW_OP_and);
+// whether block `k` mismatches, from the tests of `blocks` numbered from
+// `first`, as a balanced tree of selects so the stack only grows with its depth
+fn block_test(k:&Expr,blocks:&[Expr],first:usize)->Expr{
+    if blocks.len()==1 {
+        return blocks[0].clone();
+    }
+    let half=blocks.len()/2;
+    Expr::select(k.clone().lt((first+half) as u64), block_test(k, &blocks[..half], first), block_test(k, &blocks[half..], first+half))
+}
 
-            // else $
-            exp.op(gimli::DW_OP_dup);
-            exp.op(gimli::DW_OP_dup);
-            // exp_constu(&mut exp, rng, 0, DEFAULT_DEPTH);
-            {
-                exp.op_reg(gimli::X86_64::R15);
-                exp.op(gimli::DW_OP_dup);
-                exp.op(gimli::DW_OP_not);
-                exp.op(gimli::DW_OP_and);
-            }
-            exp.op(gimli::DW_OP_gt);
-            exp.op(gimli::DW_OP_swap);
-            // exp_constu(&mut exp, rng, 4, DEFAULT_DEPTH);
-            {
-                exp.op_reg(gimli::X86_64::RAX);
-                exp.op(gimli::DW_OP_dup);
-                exp.op(gimli::DW_OP_not);
-                exp.op(gimli::DW_OP_and);
-                exp.op_plus_uconst(1);
-                exp.op(gimli::DW_OP_dup);
-                exp.op(gimli::DW_OP_dup);
-                exp.op(gimli::DW_OP_shl);
-                exp.op(gimli::DW_OP_shl);
+fn encode_rules(rules:&[CallFrameInstruction],encoding:gimli::Encoding)->Vec<u8>{
+    let mut w = gimli::write::EndianVec::new(gimli::LittleEndian);
+    for cf in rules {
+        match cf {
+            CallFrameInstruction::ValExpression(register, exp)=>{
+                cfi::write_val_expression(&mut w,*register,exp,encoding).unwrap();
             }
-            exp.op(gimli::DW_OP_lt);
-            exp.op(gimli::DW_OP_and);
-            exp_constu(&mut exp, rng, 1, DEFAULT_DEPTH);
-            exp.op(gimli::DW_OP_minus);
-            exp.op_reg(gimli::X86_64::R14);
-            exp.op(gimli::DW_OP_and);
-            exp.op(gimli::DW_OP_swap);
-
-            // else | ==2 $
-            exp.op(gimli::DW_OP_dup);
-            exp_constu(&mut exp, rng, 2, DEFAULT_DEPTH);
-            exp.op(gimli::DW_OP_ne);
-            exp_constu(&mut exp, rng, 1, DEFAULT_DEPTH);
-            exp.op(gimli::DW_OP_minus);
-            exp.op_reg(gimli::X86_64::R15);
-            exp.op(gimli::DW_OP_and);
-            exp.op(gimli::DW_OP_swap);
-
-            // else | ==2 | ==3 $
-            exp.op(gimli::DW_OP_dup);
-            exp_constu(&mut exp, rng, 3, DEFAULT_DEPTH);
-            exp.op(gimli::DW_OP_ne);
-            exp_constu(&mut exp, rng, 1, DEFAULT_DEPTH);
-            exp.op(gimli::DW_OP_minus);
-            exp.op_reg(gimli::X86_64::R14);
-            exp_constu(&mut exp, rng, self.xor_num_a, DEFAULT_DEPTH);
-            exp.op(gimli::DW_OP_xor);
-            exp.op(gimli::DW_OP_and);
-            exp.op(gimli::DW_OP_swap);
-
-            // else | ==2 | ==3 | ==1 $
-            exp_constu(&mut exp, rng, 1, DEFAULT_DEPTH);
-            exp.op(gimli::DW_OP_ne);
-            exp_constu(&mut exp, rng, 1, DEFAULT_DEPTH);
-            exp.op(gimli::DW_OP_minus);
-
-            exp.op_reg(gimli::X86_64::R14);
-            // f(r13,r15) = (r13+hash_num)^(r13>>30)^(r15<<24)
-            {
-                exp.op_reg(gimli::X86_64::R13);
-                exp.op(gimli::DW_OP_dup);
-                exp.op_plus_uconst(self.hash_num);
-                exp.op(gimli::DW_OP_swap);
-                exp_constu(&mut exp, rng, 30, DEFAULT_DEPTH);
-                exp.op(gimli::DW_OP_shr);
-
-                exp.op_reg(gimli::X86_64::R15);
-                exp_constu(&mut exp, rng, 24, DEFAULT_DEPTH);
-                exp.op(gimli::DW_OP_shl);
+            _=>unreachable!("generate_code only emits val_expression rules"),
+        }
+    }
+    w.slice().to_vec()
+}
 
-                exp.op(gimli::DW_OP_xor);
-                exp.op(gimli::DW_OP_xor);
-            }
-            exp.op(gimli::DW_OP_xor);
-            exp.op(gimli::DW_OP_and);
+fn main() {
+    let opts=match Options::parse(std::env::args().skip(1)) {
+        Ok(opts)=>opts,
+        Err(err)=>{
+            eprintln!("error: {}\n\n{}",err,cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+    if opts.help {
+        println!("{}",cli::USAGE);
+        return;
+    }
+    if let Err(err)=run(&opts) {
+        eprintln!("error: {}",err);
+        std::process::exit(1);
+    }
+}
 
-            exp.op(gimli::DW_OP_or);
-            exp.op(gimli::DW_OP_or);
-            exp.op(gimli::DW_OP_or);
+fn run(opts:&Options)->Result<(),Box<dyn Error>>{
+    // reject encodings the unwinder cannot read, and objects we cannot
+    // write, before writing anything
+    cfi::check_encoding(opts.encoding)?;
+    if opts.format==Format::Elf&&opts.arch!=Arch::X86_64 {
+        return Err(format!("elf output is only supported for x86_64, not {}",opts.arch).into());
+    }
+    // t1_obj.cpp reads and prints the flag as hex words; only t1_str.cpp takes text
+    if opts.format==Format::Elf&&opts.flag_format!=FlagFormat::Hex {
+        return Err(format!("elf output needs a hex flag, not {}",opts.flag_format).into());
+    }
+    // ChaCha8 is portable and value-stable, unlike StdRng, so a seed always
+    // rebuilds the same challenge; ranges are drawn over u32 or u64, never
+    // usize, which rand samples differently on 32 and 64-bit hosts
+    let seed=opts.seed.unwrap_or_else(|| rand::thread_rng().gen());
+    let mut rng=ChaCha8Rng::seed_from_u64(seed);
+    let text=opts.flag_format.sample(&mut rng, &opts.flag_prefix);
+    let words=text.as_deref().map(flag::pack).transpose()?;
+    let args:Arg=rng.sample(ArgSpec { words: opts.words, flag: words, rounds: opts.rounds.clone(), cipher: opts.cipher });
+    // only t1_mem.cpp and t1_str.cpp pass a flag in memory, both on x86_64;
+    // the object's t1_obj.cpp reads two words into registers like the rest
+    if args.in_memory()&&(opts.arch!=Arch::X86_64||opts.format==Format::Elf) {
+        return Err(format!("a flag of {} words is checked in memory, which only the x86_64 cfi and raw harnesses do",args.flag.len()).into());
+    }
 
-            let cf=CallFrameInstruction::ValExpression(gimli::X86_64::R14, exp);
-            cf.simple_write(&mut w,gimli::Encoding { address_size: 8, format: gimli::Format::Dwarf64, version: 5 }).unwrap();
+    let regs=if opts.fixed_regs { opts.arch.vm_regs() } else { opts.arch.random_vm_regs(&mut rng) };
+    let source=match &opts.program {
+        Some(path)=>fs::read_to_string(path)?,
+        None=>vm::source(args.flag.len()),
+    };
+    let program=asm::assemble(&args.isa(&regs), &source).map_err(|err|format!("the VM program does not assemble: {}",err))?;
+    // a literal obfuscated at depth 0, or only through the unary cases of
+    // exp_constu, still holds its value; drawing the rules again hides it
+    let secrets=args.secrets();
+    let secrets:Vec<(&str,u64)>=secrets.iter().map(|(name,value)|(name.as_str(),*value)).collect();
+    let mut attempts=0;
+    // the bytecode is encoded once and reused for every check and the output
+    let (rules,bytecode)=loop {
+        let rules=args.generate_code(&mut rng,opts,&regs);
+        let bytecode=encode_rules(&rules, opts.encoding);
+        match leak::check(&bytecode, &secrets) {
+            Ok(())=>break (rules,bytecode),
+            Err(_) if attempts<LEAK_RETRIES=>attempts+=1,
+            Err(err)=>return Err(format!("generated rules leak a secret, raise --depth or the budget: {}",err).into()),
         }
+    };
+    {
+        let rules=vm::parse_rules(&bytecode)?;
+        // an overflow makes libgcc abort the unwind rather than report anything
+        for rule in &rules {
+            stack::analyze(&rule.expression, opts.encoding)
+                .map_err(|err|format!("rule for {}: {}",opts.arch.register_name(rule.register).unwrap_or("?"),err))?;
+        }
+        // evaluate the rules here instead of on the target, so any arch can be
+        // checked; a stream of its own keeps the build independent of it
+        let mut check_rng=ChaCha8Rng::seed_from_u64(seed);
+        check_rng.set_stream(1);
+        vm::verify(&rules, opts.encoding, &regs, &program, &args.flag, &mut check_rng)
+            .map_err(|err|format!("generated rules fail verification: {}",err))?;
+    }
 
-        w.slice().to_vec()
+    let flag=text.unwrap_or_else(|| format!("{}{{{}}}",opts.flag_prefix,args.flag.iter().map(|word|format!("{:016x}",word)).collect::<String>()));
+    match &opts.flag_out {
+        Some(path)=>writeln!(OpenOptions::new().append(true).create(true).open(path)?,"{}",flag)?,
+        None=>eprintln!("{}",flag),
     }
-}
 
-fn main() {
-    let mut rng=rand::thread_rng();
-    let args:Arg=rng.gen();
+    if let Some(path)=&opts.header {
+        let program=program.iter()
+            .map(|&op|if (op as i8)<0 { format!("(uint8_t){}",op as i8) } else { op.to_string() }).collect::<Vec<String>>().join(",");
+        // the prefix is graphic ASCII, so only quotes and backslashes need escaping
+        let prefix=opts.flag_prefix.replace('\\', "\\\\").replace('"', "\\\"");
+        fs::write(path, format!("{}#define VM_WORDS {}\n#define VM_PADDING {}\n#define VM_PROGRAM {{{}}}\n#define VM_FLAG_PREFIX \"{}\"\n",
+            regs.c_header(opts.arch),args.flag.len(),vm::PADDING,program,prefix))?;
+    }
 
-    let codes=args.generate_code(&mut rng);
-    
-    eprintln!("flag{{{:016x}{:016x}}}",args.flag_a,args.flag_b);
-    // eprintln!("len: {}",codes.len());
-    // eprintln!("{:#?}",args);
-    
-    let bytecode=codes.iter().map(|x|x.to_string()).collect::<Vec<String>>().join(",");
-    println!("asm(\".cfi_escape {}\");",bytecode);
+    let mut out:Box<dyn Write>=match &opts.output {
+        Some(path)=>Box::new(File::create(path)?),
+        None=>Box::new(io::stdout().lock()),
+    };
+    match opts.format {
+        Format::Cfi=>{
+            let bytecode=bytecode.iter().map(|x|x.to_string()).collect::<Vec<String>>().join(",");
+            writeln!(out,"// seed: {}",seed)?;
+            writeln!(out,"asm(\".cfi_escape {}\");",bytecode)?;
+        }
+        Format::Raw=>out.write_all(&bytecode)?,
+        Format::Elf=>out.write_all(&elf::write_object(rules, opts.encoding)?)?,
+    }
+    out.flush()?;
+    Ok(())
 }
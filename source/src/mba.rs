@@ -0,0 +1,147 @@
+use rand::Rng;
+
+use crate::builder::{BinOp, Expr};
+
+/// Mixed boolean-arithmetic forms known for `op`.
+fn forms(op:BinOp)->usize{
+    match op{
+        BinOp::Xor=>4,
+        BinOp::Add|BinOp::And|BinOp::Or=>3,
+        _=>0,
+    }
+}
+
+// `x` and `y` are leaves, so repeating them costs nothing at runtime
+fn expand(op:BinOp,form:usize,x:&Expr,y:&Expr)->Expr{
+    let x=||x.clone();
+    let y=||y.clone();
+    match (op,form){
+        (BinOp::Xor,0)=>(x()|y())-(x()&y()),
+        (BinOp::Xor,1)=>(x()+y())-((x()&y())<<1u64),
+        (BinOp::Xor,2)=>(x()|y())&!(x()&y()),
+        (BinOp::Xor,3)=>(!x()&y())|(x()&!y()),
+        (BinOp::Add,0)=>(x()^y())+((x()&y())<<1u64),
+        (BinOp::Add,1)=>(x()|y())+(x()&y()),
+        (BinOp::Add,2)=>((x()|y())<<1u64)-(x()^y()),
+        (BinOp::And,0)=>(x()+y())-(x()|y()),
+        (BinOp::And,1)=>(!x()|y())-!x(),
+        (BinOp::And,2)=>(x()|y())-(x()^y()),
+        (BinOp::Or,0)=>(x()+y())-(x()&y()),
+        (BinOp::Or,1)=>(x()^y())+(x()&y()),
+        (BinOp::Or,2)=>(x()&!y())+y(),
+        _=>unreachable!("no form {} for {:?}",form,op),
+    }
+}
+
+// hands `body` something it can repeat, binding `value` first unless it is a leaf
+fn share(value:Expr,body:impl FnOnce(Expr)->Expr)->Expr{
+    match value{
+        Expr::Reg(_)|Expr::Const(_)|Expr::Var(_)=>body(value),
+        _=>Expr::bind(value, body),
+    }
+}
+
+/// Replaces every xor, and, or and plus in `expr` with a randomly picked
+/// mixed boolean-arithmetic equivalent, e.g. `x^y` with `(x|y)-(x&y)`.
+///
+/// The operators of a form are rewritten again, `level` levels deep in all;
+/// level 0 returns `expr` unchanged.
+pub fn rewrite<R:Rng+?Sized>(expr:&Expr,rng:&mut R,level:u32)->Expr{
+    if level==0 {
+        return expr.clone();
+    }
+    match expr{
+        Expr::Reg(_)|Expr::Const(_)|Expr::Var(_)=>expr.clone(),
+        Expr::Not(value)=>!rewrite(value, rng, level),
+        Expr::Neg(value)=>-rewrite(value, rng, level),
+        Expr::Deref(address)=>Expr::deref(rewrite(address, rng, level)),
+        Expr::Bin(op,lhs,rhs)=>{
+            let lhs=rewrite(lhs, rng, level);
+            let rhs=rewrite(rhs, rng, level);
+            match forms(*op){
+                0=>Expr::bin(*op, lhs, rhs),
+                n=>{
+                    let form=rng.gen_range(0..n as u32) as usize;
+                    share(lhs, |x| share(rhs, |y| rewrite(&expand(*op, form, &x, &y), rng, level-1)))
+                }
+            }
+        }
+        Expr::Select(cond,then,otherwise)=>{
+            Expr::select(rewrite(cond, rng, level), rewrite(then, rng, level), rewrite(otherwise, rng, level))
+        }
+        Expr::Let(id,value,body)=>{
+            Expr::Let(*id,Box::new(rewrite(value, rng, level)),Box::new(rewrite(body, rng, level)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::collections::HashMap;
+
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    use super::*;
+
+    // what the expression computes, with `Var`s bound in `vars`
+    fn value(expr:&Expr,vars:&mut HashMap<usize,u64>)->u64{
+        match expr{
+            Expr::Const(value)=>*value,
+            Expr::Var(id)=>vars[id],
+            Expr::Not(value)=>!self::value(value, vars),
+            Expr::Neg(value)=>self::value(value, vars).wrapping_neg(),
+            Expr::Let(id,value,body)=>{
+                let value=self::value(value, vars);
+                vars.insert(*id, value);
+                self::value(body, vars)
+            }
+            Expr::Bin(op,lhs,rhs)=>{
+                let (x,y)=(self::value(lhs, vars),self::value(rhs, vars));
+                match op{
+                    BinOp::Add=>x.wrapping_add(y),
+                    BinOp::Sub=>x.wrapping_sub(y),
+                    BinOp::Mul=>x.wrapping_mul(y),
+                    BinOp::And=>x&y,
+                    BinOp::Or=>x|y,
+                    BinOp::Xor=>x^y,
+                    BinOp::Shl=>x.wrapping_shl(y as u32),
+                    _=>unreachable!("{:?} is not in any form", op),
+                }
+            }
+            _=>unreachable!("{:?} is not in any form", expr),
+        }
+    }
+
+    const OPS:[BinOp;4]=[BinOp::Xor,BinOp::Add,BinOp::And,BinOp::Or];
+
+    #[test]
+    fn every_form_is_equivalent(){
+        let mut rng=ChaCha8Rng::seed_from_u64(0);
+        for op in OPS {
+            for form in 0..forms(op) {
+                for _ in 0..64 {
+                    let (x,y):(u64,u64)=(rng.gen(),rng.gen());
+                    let expected=value(&Expr::bin(op, x, y), &mut HashMap::new());
+                    let got=value(&expand(op, form, &Expr::from(x), &Expr::from(y)), &mut HashMap::new());
+                    assert_eq!(got, expected, "form {} of {:?} on {:#x}, {:#x}", form, op, x, y);
+                }
+            }
+        }
+        assert_eq!(OPS.iter().map(|&op|forms(op)).sum::<usize>(), 13);
+    }
+
+    #[test]
+    fn rewrite_keeps_the_value(){
+        let mut rng=ChaCha8Rng::seed_from_u64(1);
+        for _ in 0..64 {
+            let (x,y,z):(u64,u64,u64)=(rng.gen(),rng.gen(),rng.gen());
+            // nested operations get bound before their forms repeat them
+            let expr=((Expr::from(x)^y)+(Expr::from(z)&x))|!(Expr::from(y)^z);
+            let expected=value(&expr, &mut HashMap::new());
+            for level in 0..4 {
+                assert_eq!(value(&rewrite(&expr, &mut rng, level), &mut HashMap::new()), expected);
+            }
+        }
+    }
+}
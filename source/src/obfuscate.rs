@@ -0,0 +1,245 @@
+use gimli::write::{EndianVec, Expression, Writer};
+use gimli::{DwOp, LittleEndian, Register};
+use rand::seq::SliceRandom;
+use rand::Rng;
+
+use crate::opaque::{self, OpaqueSources};
+
+pub const DEFAULT_DEPTH:u64=9;
+
+// a leaf holding a random u64: 9 bytes as DW_OP_const8u/const8s, and the
+// opcode and a LEB128 of 9.5 bytes on average as DW_OP_constu/consts
+const RANDOM_LEAF_SIZE:f64=9.75;
+
+pub(crate) fn uleb128_size(value:u64)->usize{
+    let bits=64-value.leading_zeros() as usize;
+    bits.div_ceil(7).max(1)
+}
+
+// DW_OP_reg0 to reg31 hold the register in the opcode, DW_OP_regx takes a ULEB
+pub(crate) fn reg_size(register:Register)->usize{
+    if register.0<32 { 1 } else { 1+uleb128_size(register.0.into()) }
+}
+
+pub(crate) fn sleb128_size(value:i64)->usize{
+    // the sign bit needs room too
+    let bits=65-if value<0 { value.leading_ones() } else { value.leading_zeros() } as usize;
+    bits.div_ceil(7)
+}
+
+// every single operation that pushes `num`, as bytecode
+fn leaf_encodings(num:u64)->Vec<Vec<u8>>{
+    let mut encodings=Vec::new();
+    let mut push=|op:DwOp,operand:&[u8]|{
+        let mut bytes=vec![op.0];
+        bytes.extend_from_slice(operand);
+        encodings.push(bytes);
+    };
+    if num<32 {
+        push(DwOp(gimli::DW_OP_lit0.0+num as u8), &[]);
+    }
+    if let Ok(value)=u8::try_from(num) {
+        push(gimli::DW_OP_const1u, &value.to_le_bytes());
+    }
+    if let Ok(value)=u16::try_from(num) {
+        push(gimli::DW_OP_const2u, &value.to_le_bytes());
+    }
+    if let Ok(value)=u32::try_from(num) {
+        push(gimli::DW_OP_const4u, &value.to_le_bytes());
+    }
+    push(gimli::DW_OP_const8u, &num.to_le_bytes());
+    // the signed forms sign extend, the stack is 64 bits wide
+    let signed=num as i64;
+    if let Ok(value)=i8::try_from(signed) {
+        push(gimli::DW_OP_const1s, &value.to_le_bytes());
+    }
+    if let Ok(value)=i16::try_from(signed) {
+        push(gimli::DW_OP_const2s, &value.to_le_bytes());
+    }
+    if let Ok(value)=i32::try_from(signed) {
+        push(gimli::DW_OP_const4s, &value.to_le_bytes());
+    }
+    push(gimli::DW_OP_const8s, &signed.to_le_bytes());
+    let mut leb=EndianVec::new(LittleEndian);
+    leb.write_uleb128(num).unwrap();
+    push(gimli::DW_OP_constu, leb.slice());
+    let mut leb=EndianVec::new(LittleEndian);
+    leb.write_sleb128(signed).unwrap();
+    push(gimli::DW_OP_consts, leb.slice());
+    encodings
+}
+
+/// Expected size of the constant operation [`exp_leaf`] emits for `num`.
+pub fn leaf_size(num:u64)->f64{
+    let encodings=leaf_encodings(num);
+    encodings.iter().map(Vec::len).sum::<usize>() as f64/encodings.len() as f64
+}
+
+/// Pushes `num` with one of the constant operations that can hold it, picked
+/// at random so the leaves have no telltale encoding, and returns its size.
+pub fn exp_leaf<R:Rng+?Sized>(exp:&mut Expression,rng:&mut R,num:u64)->usize{
+    let encodings=leaf_encodings(num);
+    let bytes=encodings.choose(rng).unwrap();
+    // gimli has no operation for most of these, but writes raw opcodes as they are
+    for &byte in bytes {
+        exp.op(DwOp(byte));
+    }
+    bytes.len()
+}
+
+/// Expected number of bytes [`exp_constu_from`] emits for `num` at `depth`,
+/// with or without opaque sources.
+///
+/// Every case but the unary ones has two or three children, so this grows
+/// by about 1.9x per level.
+pub fn expected_size(num:u64,depth:u64,opaque:bool)->f64{
+    if depth==0 {
+        return leaf_size(num);
+    }
+    // below the top level the leaves are mostly random values
+    let mut size=RANDOM_LEAF_SIZE;
+    for _ in 0..depth {
+        size=if opaque {
+            // a constant gadget and one child, a predicate and two
+            (17.0*size+14.0+(size+6.0)+(2.0*size+15.0))/11.0
+        } else {
+            (17.0*size+14.0)/9.0
+        };
+    }
+    size
+}
+
+// children for `a` and `b` in random order, for a commutative operation
+fn commutative<R:Rng+?Sized>(exp:&mut Expression,rng:&mut R,(a,b):(u64,u64),depth:u64,sources:OpaqueSources<'_>)->usize{
+    let (a,b)=if rng.gen() { (b,a) } else { (a,b) };
+    exp_constu_from(exp, rng, a, depth, sources)+exp_constu_from(exp, rng, b, depth, sources.shifted(1))
+}
+
+/// Emits an obfuscated computation of `num` and returns its size in bytes.
+pub fn exp_constu<R:Rng+?Sized>(exp:&mut Expression, rng:&mut R,num:u64,depth:u64)->usize{
+    exp_constu_from(exp, rng, num, depth, OpaqueSources::default())
+}
+
+/// Like [`exp_constu`], but also hides values behind opaque constants and
+/// predicates built from `sources`.
+pub fn exp_constu_from<R:Rng+?Sized>(exp:&mut Expression, rng:&mut R,num:u64,depth:u64,sources:OpaqueSources<'_>)->usize{
+    if depth==0 {
+        return exp_leaf(exp, rng, num);
+    }
+    // the gadgets need a source in reach, the predicate one more value up
+    let cases=match (sources.is_empty(),sources.shifted(1).is_empty()){
+        (true,_)=>9,
+        (false,true)=>10,
+        (false,false)=>11,
+    };
+    let ty:u32=rng.gen_range(0..cases);
+    let mut size=0;
+    match ty{
+        0=>{
+            let xor_num:u64=rng.gen();
+            size+=commutative(exp, rng, (xor_num,xor_num^num), depth-1, sources);
+            exp.op(gimli::DW_OP_xor);
+            size+=1;
+        }
+        1=>{
+            let add_num:u64=rng.gen();
+            size+=exp_constu_from(exp, rng, add_num.wrapping_add(num), depth-1, sources);
+            size+=exp_constu_from(exp, rng, add_num, depth-1, sources.shifted(1));
+            exp.op(gimli::DW_OP_minus);
+            size+=1;
+        }
+        2=>{
+            let add_num:u64=rng.gen();
+            size+=commutative(exp, rng, (num.wrapping_sub(add_num),add_num), depth-1, sources);
+            exp.op(gimli::DW_OP_plus);
+            size+=1;
+        }
+        3=>{
+            let num1:u64=rng.gen::<u64>()&num;
+            let mut num2:u64=rng.gen::<u64>()&num;
+            num2|=num&!(num1|num2);
+            assert_eq!(num1|num2,num);
+            size+=commutative(exp, rng, (num1,num2), depth-1, sources);
+            exp.op(gimli::DW_OP_or);
+            size+=1;
+        }
+        4=>{
+            let num1:u64=rng.gen::<u64>()&!num;
+            let mut num2:u64=rng.gen::<u64>()&!num;
+            num2|=!num&!(num1|num2);
+            assert_eq!(!num1&!num2,num);
+            size+=commutative(exp, rng, (!num1,!num2), depth-1, sources);
+            exp.op(gimli::DW_OP_and);
+            size+=1;
+        }
+        5=>{
+            size+=exp_constu_from(exp, rng, !num, depth-1, sources);
+            exp.op(gimli::DW_OP_not);
+            size+=1;
+        }
+        6=>{
+            exp.op(gimli::DW_OP_dup);
+            size+=exp_constu_from(exp, rng, num, depth-1, sources.shifted(1));
+            exp.op(gimli::DW_OP_swap);
+            exp.op(gimli::DW_OP_drop);
+            size+=3;
+        }
+        7=>{
+            let rand_num:u64=rng.gen();
+            size+=exp_constu_from(exp, rng, rand_num, depth-1, sources);
+            size+=exp_constu_from(exp, rng, num, depth-1, sources.shifted(1));
+            exp.op(gimli::DW_OP_swap);
+            exp.op(gimli::DW_OP_drop);
+            size+=2;
+        }
+        8=>{
+            let rand_num1:u64=rng.gen();
+            let rand_num2:u64=rng.gen();
+            size+=exp_constu_from(exp, rng, rand_num1, depth-1, sources);
+            size+=exp_constu_from(exp, rng, rand_num2, depth-1, sources.shifted(1));
+            size+=exp_constu_from(exp, rng, num, depth-1, sources.shifted(2));
+            exp.op(gimli::DW_OP_rot);
+            exp.op(gimli::DW_OP_drop);
+            exp.op(gimli::DW_OP_drop);
+            size+=3;
+        }
+        9=>{
+            // k op c, with c chosen so that it comes out as num
+            let (value,gadget)=opaque::constant(exp, rng, sources);
+            let (child,op)=match (value,rng.gen_range(0..4)){
+                (0,0)=>(num,gimli::DW_OP_xor),
+                (0,1)=>(num,gimli::DW_OP_plus),
+                (0,2)=>(num,gimli::DW_OP_or),
+                (0,_)=>(num.wrapping_neg(),gimli::DW_OP_minus),
+                (1,0)=>(num^1,gimli::DW_OP_xor),
+                (1,1)=>(num.wrapping_sub(1),gimli::DW_OP_plus),
+                (1,2)=>(num,gimli::DW_OP_mul),
+                (1,_)=>(1u64.wrapping_sub(num),gimli::DW_OP_minus),
+                (_,0)=>(!num,gimli::DW_OP_xor),
+                (_,1)=>(num.wrapping_add(1),gimli::DW_OP_plus),
+                (_,2)=>(num,gimli::DW_OP_and),
+                (_,_)=>(!num,gimli::DW_OP_minus),
+            };
+            size+=gadget;
+            size+=exp_constu_from(exp, rng, child, depth-1, sources.shifted(1));
+            exp.op(op);
+            size+=1;
+        }
+        10=>{
+            // p ? num : junk, with a p that always holds
+            size+=exp_constu_from(exp, rng, num, depth-1, sources);
+            size+=opaque::predicate(exp, rng, sources.shifted(1));
+            // mask = -p, then num & mask | junk & ~mask
+            for op in [gimli::DW_OP_neg,gimli::DW_OP_dup,gimli::DW_OP_rot,gimli::DW_OP_and,gimli::DW_OP_swap,gimli::DW_OP_not] {
+                exp.op(op);
+            }
+            let junk:u64=rng.gen();
+            size+=exp_constu_from(exp, rng, junk, depth-1, sources.shifted(2));
+            exp.op(gimli::DW_OP_and);
+            exp.op(gimli::DW_OP_or);
+            size+=8;
+        }
+        _=>{}
+    }
+    size
+}
@@ -0,0 +1,201 @@
+use gimli::write::Expression;
+use gimli::{
+    DwOp, Register, DW_OP_and, DW_OP_dup, DW_OP_eq, DW_OP_lit0, DW_OP_lit1, DW_OP_lit2, DW_OP_lit3, DW_OP_minus, DW_OP_mul,
+    DW_OP_ne, DW_OP_neg, DW_OP_not, DW_OP_or, DW_OP_plus, DW_OP_shl, DW_OP_swap, DW_OP_xor,
+};
+use rand::Rng;
+
+use crate::obfuscate::{reg_size, sleb128_size, uleb128_size};
+
+/// Runtime values the opaque gadgets may be built from. Which values they
+/// are never matters, only that the unwinder can read them.
+#[derive(Debug,Clone,Copy,Default)]
+pub struct OpaqueSources<'a>{
+    pub registers:&'a [Register],
+    /// values on the stack above the CFA the unwinder seeded it with, if known
+    pub cfa_depth:Option<usize>,
+}
+
+impl<'a> OpaqueSources<'a>{
+    /// Whether there is nothing a gadget could load.
+    pub fn is_empty(&self)->bool{
+        self.registers.is_empty()&&self.cfa().is_none()
+    }
+
+    // the CFA's depth if it is in reach: libgcc's DW_OP_pick cannot reach
+    // the bottom of the stack, only DW_OP_dup and DW_OP_over can
+    fn cfa(&self)->Option<usize>{
+        self.cfa_depth.filter(|&depth|depth<2)
+    }
+
+    /// The same sources once `pushed` more values are on the stack.
+    pub fn shifted(self,pushed:usize)->Self{
+        OpaqueSources { cfa_depth: self.cfa_depth.map(|depth|depth+pushed), ..self }
+    }
+}
+
+// pushes one of the sources, maybe with an offset, and returns its size
+fn load<R:Rng+?Sized>(exp:&mut Expression,rng:&mut R,sources:OpaqueSources<'_>)->usize{
+    let cfa=sources.cfa();
+    let choice=rng.gen_range(0..(sources.registers.len()+cfa.is_some() as usize) as u32) as usize;
+    match sources.registers.get(choice){
+        Some(&register)=>{
+            let prefix=reg_size(register);
+            if rng.gen() {
+                exp.op_reg(register);
+                prefix
+            } else {
+                let offset=rng.gen_range(-0x1000..0x1000);
+                exp.op_breg(register, offset);
+                prefix+sleb128_size(offset)
+            }
+        }
+        None=>{
+            let offset=rng.gen_range(1..0x1000);
+            exp.op_pick(cfa.unwrap() as u8);
+            exp.op_plus_uconst(offset);
+            2+uleb128_size(offset)
+        }
+    }
+}
+
+/// Emits `ops`, which take no operands, and returns their size.
+pub(crate) fn ops(exp:&mut Expression,ops:&[DwOp])->usize{
+    for &op in ops {
+        exp.op(op);
+    }
+    ops.len()
+}
+
+/// Pushes a value that is the same whatever the sources hold, and returns
+/// it with the number of bytes emitted.
+pub fn constant<R:Rng+?Sized>(exp:&mut Expression,rng:&mut R,sources:OpaqueSources<'_>)->(u64,usize){
+    let size=load(exp, rng, sources);
+    let (value,gadget):(u64,&[DwOp])=match rng.gen_range(0..7){
+        // x ^ x = 0
+        0=>(0,&[DW_OP_dup,DW_OP_xor]),
+        // x & ~x = 0: no bit is set in both
+        1=>(0,&[DW_OP_dup,DW_OP_not,DW_OP_and]),
+        // x - x = 0
+        2=>(0,&[DW_OP_dup,DW_OP_minus]),
+        // x*(x+1) & 1 = 0: one of two consecutive integers is even, and the
+        // low bit of a product does not depend on the bits that wrap away
+        3=>(0,&[DW_OP_dup,DW_OP_lit1,DW_OP_plus,DW_OP_mul,DW_OP_lit1,DW_OP_and]),
+        // x | ~x = ~0: every bit is set in one of the two
+        4=>(!0,&[DW_OP_dup,DW_OP_not,DW_OP_or]),
+        // x + ~x = ~0: no bit is set in both, so the sum never carries and
+        // equals x | ~x
+        5=>(!0,&[DW_OP_dup,DW_OP_not,DW_OP_plus]),
+        // (x | 1) & 1 = 1
+        _=>(1,&[DW_OP_lit1,DW_OP_or,DW_OP_lit1,DW_OP_and]),
+    };
+    (value,size+ops(exp, gadget))
+}
+
+/// Pushes 1, computed as a comparison that holds whatever the sources
+/// hold, and returns the number of bytes emitted.
+pub fn predicate<R:Rng+?Sized>(exp:&mut Expression,rng:&mut R,sources:OpaqueSources<'_>)->usize{
+    let size=load(exp, rng, sources);
+    let gadget:&[DwOp]=match rng.gen_range(0..6){
+        // x*x & 3 != 2: a square is 0 or 1 mod 4, and wrapping at 2^64
+        // keeps the residue mod 4
+        0=>&[DW_OP_dup,DW_OP_mul,DW_OP_lit3,DW_OP_and,DW_OP_lit2,DW_OP_ne],
+        // x*(x+1) & 1 == 0, see `constant`
+        1=>&[DW_OP_dup,DW_OP_lit1,DW_OP_plus,DW_OP_mul,DW_OP_lit1,DW_OP_and,DW_OP_lit0,DW_OP_eq],
+        // x & ~x == 0
+        2=>&[DW_OP_dup,DW_OP_not,DW_OP_and,DW_OP_lit0,DW_OP_eq],
+        // x | 1 != 0: the low bit is set
+        3=>&[DW_OP_lit1,DW_OP_or,DW_OP_lit0,DW_OP_ne],
+        // (x ^ ~x) + 1 == 0: x ^ ~x = ~0, which wraps to 0
+        4=>&[DW_OP_dup,DW_OP_not,DW_OP_xor,DW_OP_lit1,DW_OP_plus,DW_OP_lit0,DW_OP_eq],
+        // x - (x << 1) == -x, as x - 2x = -x holds mod 2^64
+        _=>&[DW_OP_dup,DW_OP_dup,DW_OP_lit1,DW_OP_shl,DW_OP_minus,DW_OP_swap,DW_OP_neg,DW_OP_eq],
+    };
+    size+ops(exp, gadget)
+}
+
+#[cfg(test)]
+mod tests{
+    use rand::seq::SliceRandom;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    use super::*;
+    use crate::cfi::{expression_bytes, DEFAULT_ENCODING};
+    use crate::eval::{evaluate, Memory, Registers};
+    use crate::obfuscate::exp_constu_from;
+
+    const REGISTERS:[Register;3]=[Register(3),Register(12),Register(40)];
+
+    // mostly random, but the values at the edges of the arithmetic too
+    fn value(rng:&mut ChaCha8Rng)->u64{
+        *[0,1,2,!0,1<<63,(1<<63)-1,rng.gen(),rng.gen()].choose(rng).unwrap()
+    }
+
+    // random sources, and how many values are pushed before the gadget
+    fn sources(rng:&mut ChaCha8Rng)->(OpaqueSources<'static>,usize){
+        match rng.gen_range(0..3){
+            0=>(OpaqueSources { registers: &REGISTERS, cfa_depth: None },rng.gen_range(0..3)),
+            1=>{
+                let depth=rng.gen_range(0..2);
+                (OpaqueSources { registers: &[], cfa_depth: Some(depth) },depth)
+            }
+            _=>{
+                let depth=rng.gen_range(0..4);
+                (OpaqueSources { registers: &REGISTERS, cfa_depth: Some(depth) },depth)
+            }
+        }
+    }
+
+    // runs what `emit` emits above `pushed` zeros, with random registers and CFA,
+    // checking the size it returns; gives the value left on top
+    fn run(rng:&mut ChaCha8Rng,pushed:usize,emit:impl FnOnce(&mut Expression,&mut ChaCha8Rng)->usize)->u64{
+        let mut exp=Expression::new();
+        for _ in 0..pushed {
+            exp.op(DW_OP_lit0);
+        }
+        let size=emit(&mut exp, rng);
+        let bytecode=expression_bytes(&exp, DEFAULT_ENCODING).unwrap();
+        assert_eq!(size, bytecode.len()-pushed);
+        let regs:Registers=REGISTERS.iter().map(|&register|(register,value(rng))).collect();
+        let cfa=value(rng);
+        evaluate(&bytecode, DEFAULT_ENCODING, &regs, &Memory::new(), cfa).unwrap()
+    }
+
+    #[test]
+    fn constants_hold(){
+        let mut rng=ChaCha8Rng::seed_from_u64(1);
+        for _ in 0..1000 {
+            let (sources,pushed)=sources(&mut rng);
+            let mut expected=None;
+            let value=run(&mut rng, pushed, |exp,rng| {
+                let (value,size)=constant(exp, rng, sources);
+                expected=Some(value);
+                size
+            });
+            assert_eq!(Some(value), expected);
+        }
+    }
+
+    #[test]
+    fn predicates_hold(){
+        let mut rng=ChaCha8Rng::seed_from_u64(2);
+        for _ in 0..1000 {
+            let (sources,pushed)=sources(&mut rng);
+            assert_eq!(run(&mut rng, pushed, |exp,rng|predicate(exp, rng, sources)), 1);
+        }
+    }
+
+    #[test]
+    fn cfa_out_of_reach(){
+        // with no registers and the CFA too deep, exp_constu_from keeps to arithmetic
+        let mut rng=ChaCha8Rng::seed_from_u64(3);
+        for depth in 1..5 {
+            for _ in 0..50 {
+                let num=value(&mut rng);
+                let sources=OpaqueSources { registers: &[], cfa_depth: Some(depth) };
+                assert_eq!(run(&mut rng, depth, |exp,rng|exp_constu_from(exp, rng, num, 3, sources)), num);
+            }
+        }
+    }
+}
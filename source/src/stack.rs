@@ -0,0 +1,171 @@
+use std::fmt;
+
+use gimli::read::{EndianSlice, Operation};
+use gimli::{Encoding, LittleEndian};
+
+use crate::eval::{branch_target, STACK_LIMIT};
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub enum StackError{
+    Parse(gimli::read::Error),
+    Unsupported(String),
+    Underflow(usize),
+    Overflow(usize),
+    BadBranch(usize),
+    /// two paths reach the offset with different stack heights
+    Mismatch(usize),
+    /// the expression leaves this many values above the CFA instead of one
+    Unbalanced(usize),
+}
+
+impl fmt::Display for StackError{
+    fn fmt(&self,f:&mut fmt::Formatter<'_>)->fmt::Result{
+        match self{
+            StackError::Parse(err)=>write!(f, "malformed expression: {}", err),
+            StackError::Unsupported(op)=>write!(f, "unsupported operation {}", op),
+            StackError::Underflow(pc)=>write!(f, "stack underflow at offset {}", pc),
+            StackError::Overflow(pc)=>write!(f, "more than {} stack entries at offset {}", STACK_LIMIT, pc),
+            StackError::BadBranch(pc)=>write!(f, "branch out of the expression at offset {}", pc),
+            StackError::Mismatch(pc)=>write!(f, "paths joining at offset {} disagree on the stack height", pc),
+            StackError::Unbalanced(extra)=>write!(f, "expression leaves {} values instead of 1", extra),
+        }
+    }
+}
+
+impl std::error::Error for StackError{}
+
+impl From<gimli::read::Error> for StackError{
+    fn from(err:gimli::read::Error)->Self{
+        StackError::Parse(err)
+    }
+}
+
+/// Stack use of an expression, counting the CFA the unwinder seeds it with.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct StackUsage{
+    pub max_depth:usize,
+    pub final_depth:usize,
+}
+
+// values an operation pops and pushes
+fn effect(op:&Operation<EndianSlice<'_,LittleEndian>>)->Result<(usize,usize),StackError>{
+    Ok(match op{
+        Operation::Nop|Operation::Skip { .. }=>(0,0),
+        Operation::Drop|Operation::Bra { .. }=>(1,0),
+        Operation::Pick { index }=>(*index as usize+1,*index as usize+2),
+        Operation::Swap=>(2,2),
+        Operation::Rot=>(3,3),
+        Operation::Abs|Operation::Neg|Operation::Not|Operation::PlusConstant { .. }
+        |Operation::Deref { space: false, .. }=>(1,1),
+        Operation::And|Operation::Or|Operation::Xor|Operation::Plus|Operation::Minus|Operation::Mul
+        |Operation::Shl|Operation::Shr|Operation::Shra|Operation::Div|Operation::Mod
+        |Operation::Eq|Operation::Ne|Operation::Lt|Operation::Le|Operation::Gt|Operation::Ge=>(2,1),
+        Operation::UnsignedConstant { .. }|Operation::SignedConstant { .. }
+        |Operation::Register { .. }|Operation::RegisterOffset { .. }=>(0,1),
+        _=>return Err(StackError::Unsupported(format!("{:?}",op))),
+    })
+}
+
+/// Walks every path through `bytecode` without running it and works out how
+/// deep the stack gets, failing if that exceeds libgcc's [`STACK_LIMIT`]
+/// anywhere or the expression does not leave exactly one value on the CFA.
+pub fn analyze(bytecode:&[u8],encoding:Encoding)->Result<StackUsage,StackError>{
+    // the depth each reachable offset is entered with
+    let mut depth_at:Vec<Option<usize>>=vec![None;bytecode.len()+1];
+    let mut pending=vec![(0usize,1usize)];
+    let mut max_depth=1;
+    while let Some((pc,depth))=pending.pop() {
+        match depth_at[pc]{
+            Some(seen) if seen==depth=>continue,
+            Some(_)=>return Err(StackError::Mismatch(pc)),
+            None=>depth_at[pc]=Some(depth),
+        }
+        if pc==bytecode.len() {
+            continue;
+        }
+        let mut reader=EndianSlice::new(&bytecode[pc..], LittleEndian);
+        let op=Operation::parse(&mut reader, encoding)?;
+        let next=bytecode.len()-reader.len();
+        let (pops,pushes)=effect(&op)?;
+        // libgcc's DW_OP_pick will not reach the bottom entry
+        let needs=if bytecode[pc]==gimli::DW_OP_pick.0 { pops+1 } else { pops };
+        if depth<needs {
+            return Err(StackError::Underflow(pc));
+        }
+        let depth=depth-pops+pushes;
+        if depth>STACK_LIMIT {
+            return Err(StackError::Overflow(pc));
+        }
+        max_depth=max_depth.max(depth);
+        match op{
+            Operation::Skip { target }=>{
+                pending.push((branch_target(next, target, bytecode.len()).ok_or(StackError::BadBranch(pc))?,depth));
+            }
+            Operation::Bra { target }=>{
+                pending.push((branch_target(next, target, bytecode.len()).ok_or(StackError::BadBranch(pc))?,depth));
+                pending.push((next,depth));
+            }
+            _=>pending.push((next,depth)),
+        }
+    }
+    let final_depth=depth_at[bytecode.len()].unwrap_or(0);
+    if final_depth!=2 {
+        return Err(StackError::Unbalanced(final_depth.saturating_sub(1)));
+    }
+    Ok(StackUsage { max_depth, final_depth })
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::cfi::DEFAULT_ENCODING;
+    use gimli::{DW_OP_bra, DW_OP_drop, DW_OP_lit0, DW_OP_lit1, DW_OP_lit2, DW_OP_pick, DW_OP_plus, DW_OP_skip};
+
+    fn analyze(bytecode:&[u8])->Result<StackUsage,StackError>{
+        super::analyze(bytecode, DEFAULT_ENCODING)
+    }
+
+    #[test]
+    fn balanced(){
+        assert_eq!(analyze(&[DW_OP_lit1.0,DW_OP_lit2.0,DW_OP_plus.0]), Ok(StackUsage { max_depth: 3, final_depth: 2 }));
+        // both arms of the branch push one value
+        let bytecode=[DW_OP_lit0.0,DW_OP_bra.0,4,0,DW_OP_lit1.0,DW_OP_skip.0,1,0,DW_OP_lit2.0];
+        assert_eq!(analyze(&bytecode), Ok(StackUsage { max_depth: 2, final_depth: 2 }));
+    }
+
+    #[test]
+    fn unbalanced(){
+        assert_eq!(analyze(&[]), Err(StackError::Unbalanced(0)));
+        assert_eq!(analyze(&[DW_OP_lit1.0,DW_OP_lit2.0]), Err(StackError::Unbalanced(2)));
+    }
+
+    #[test]
+    fn overflow(){
+        // the CFA and 63 literals fill the stack, the next one overflows it
+        let mut bytecode=vec![DW_OP_lit0.0;STACK_LIMIT-1];
+        bytecode.extend([DW_OP_plus.0;STACK_LIMIT-2]);
+        assert!(analyze(&bytecode).is_ok());
+        bytecode.insert(0, DW_OP_lit0.0);
+        assert_eq!(analyze(&bytecode), Err(StackError::Overflow(STACK_LIMIT-1)));
+    }
+
+    #[test]
+    fn mismatch(){
+        // the branch skips the literal the fallthrough pushes
+        let bytecode=[DW_OP_lit0.0,DW_OP_bra.0,1,0,DW_OP_lit1.0,DW_OP_lit2.0];
+        assert_eq!(analyze(&bytecode), Err(StackError::Mismatch(5)));
+    }
+
+    #[test]
+    fn underflow(){
+        assert_eq!(analyze(&[DW_OP_plus.0]), Err(StackError::Underflow(0)));
+        // libgcc's pick cannot reach the CFA
+        assert_eq!(analyze(&[DW_OP_pick.0,0]), Err(StackError::Underflow(0)));
+        assert_eq!(analyze(&[DW_OP_drop.0,DW_OP_lit0.0,DW_OP_plus.0]), Err(StackError::Underflow(2)));
+    }
+
+    #[test]
+    fn bad_branch(){
+        assert_eq!(analyze(&[DW_OP_lit0.0,DW_OP_skip.0,0x10,0]), Err(StackError::BadBranch(1)));
+    }
+}
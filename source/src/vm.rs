@@ -0,0 +1,227 @@
+use std::fmt;
+
+use gimli::read::{EndianSlice, Reader};
+use gimli::{Encoding, LittleEndian, Register};
+use rand::Rng;
+
+use crate::arch::VmRegs;
+use crate::eval::{self, EvalError, Memory, Registers};
+
+/// The VM program for a two-word flag, in the assembly [`asm`](crate::asm) reads.
+pub const SOURCE:&str="\
+        whiten
+round:  loop done       ; on to done once the rounds are up
+        count
+        step0
+        step1
+        jump round
+done:   whiten
+        check
+        halt
+";
+
+/// The most words a flag can have. Every block adds its expected words to
+/// the `next` rule and its rounds to the program, so building and verifying
+/// the rules slows down with the square of the length; 16 takes half a minute.
+pub const MAX_WORDS:usize=16;
+
+/// The zero bytes a harness leaves after a flag in memory.
+pub const PADDING:usize=16;
+
+// where `check_flag` puts a flag longer than two words
+const FLAG_ADDRESS:u64=0x5555_5555_a000;
+
+// the harnesses loop until the program halts, with no limit of their own;
+// this only keeps a broken pc rule from hanging the verification
+const MAX_STEPS:usize=1<<16;
+
+/// The most rounds a cipher may run, so the built-in program for the
+/// longest flag halts within the steps the verification allows: a block
+/// takes 5 steps a round and 5 more, and `end` one.
+pub const MAX_ROUNDS:u64=((MAX_STEPS-1)/(MAX_WORDS/2)/5-1) as u64;
+
+/// How many wrong flags `verify` makes sure are rejected.
+pub const WRONG_FLAGS:usize=4;
+
+// stand-in for the CFA the unwinder seeds each expression with
+const CFA:u64=0x7ffd_dead_b000;
+
+/// One `DW_CFA_val_expression` rule.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Rule{
+    pub register:Register,
+    pub expression:Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum VmError{
+    Parse(gimli::read::Error),
+    UnexpectedInstruction(u8),
+    Eval(Register,EvalError),
+    NoHalt,
+    /// the real flag left this value in the accumulator
+    FlagRejected(u64),
+    WrongFlagAccepted(Vec<u64>),
+}
+
+impl fmt::Display for VmError{
+    fn fmt(&self,f:&mut fmt::Formatter<'_>)->fmt::Result{
+        match self{
+            VmError::Parse(err)=>write!(f, "malformed CFA instructions: {}", err),
+            VmError::UnexpectedInstruction(op)=>write!(f, "unexpected CFA instruction {:#x}", op),
+            VmError::Eval(register,err)=>write!(f, "rule for register {}: {}", register.0, err),
+            VmError::NoHalt=>write!(f, "the program does not halt"),
+            VmError::FlagRejected(acc)=>write!(f, "the flag is rejected, the accumulator ends up {:#x}", acc),
+            VmError::WrongFlagAccepted(flag)=>{
+                write!(f, "the wrong flag ")?;
+                for word in flag {
+                    write!(f, "{:016x}", word)?;
+                }
+                write!(f, " is accepted")
+            }
+        }
+    }
+}
+
+impl std::error::Error for VmError{}
+
+impl From<gimli::read::Error> for VmError{
+    fn from(err:gimli::read::Error)->Self{
+        VmError::Parse(err)
+    }
+}
+
+/// Splits the emitted CFA bytes back into their val_expression rules.
+pub fn parse_rules(bytes:&[u8])->Result<Vec<Rule>,VmError>{
+    let mut reader=EndianSlice::new(bytes, LittleEndian);
+    let mut rules=Vec::new();
+    while !reader.is_empty() {
+        let op=reader.read_u8()?;
+        if op!=gimli::DW_CFA_val_expression.0 {
+            return Err(VmError::UnexpectedInstruction(op));
+        }
+        let register=Register(reader.read_uleb128_u16()?);
+        let len=reader.read_uleb128()? as usize;
+        let expression=reader.split(len)?.slice().to_vec();
+        rules.push(Rule { register, expression });
+    }
+    Ok(rules)
+}
+
+/// Runs `program` the way the host harness does: load the opcode into the
+/// pc register, let the unwinder apply every rule to the pre-unwind
+/// registers, then advance by the pc delta until it is zero or leaves the
+/// program.
+///
+/// `regs` holds the initial VM state plus whatever the opaque registers
+/// contain, and `memory` what the rules can load; the final register file
+/// is returned.
+pub fn run(rules:&[Rule],encoding:Encoding,vm:&VmRegs,program:&[u8],mut regs:Registers,memory:&Memory)->Result<Registers,VmError>{
+    let mut i:u64=0;
+    for _ in 0..MAX_STEPS {
+        // the harness loads 8 bytes starting at the opcode
+        let mut word=[0u8;8];
+        for (k,byte) in word.iter_mut().enumerate() {
+            *byte=program.get(i as usize+k).copied().unwrap_or(0);
+        }
+        regs.insert(vm.pc, u64::from_le_bytes(word));
+
+        let mut unwound=regs.clone();
+        for rule in rules {
+            let value=eval::evaluate(&rule.expression, encoding, &regs, memory, CFA).map_err(|err|VmError::Eval(rule.register,err))?;
+            unwound.insert(rule.register, value);
+        }
+        regs=unwound;
+
+        let delta=regs[&vm.pc];
+        i=i.wrapping_add(delta);
+        if delta==0||i>=program.len() as u64 {
+            return Ok(regs);
+        }
+    }
+    Err(VmError::NoHalt)
+}
+
+/// The VM program for a flag of `words` words.
+///
+/// Two words are checked in the flag registers by [`SOURCE`]. Longer flags
+/// are read from memory two words at a time: each block is loaded, runs the
+/// same rounds and is checked against the expected block `next` names, and
+/// `end` fails if any block did.
+pub fn source(words:usize)->String{
+    if words<=2 {
+        return SOURCE.to_string();
+    }
+    let mut source=String::new();
+    for block in 0..words/2 {
+        source+=&format!("\
+        load
+        whiten
+round{0}: loop done{0}
+        count
+        step0
+        step1
+        jump round{0}
+done{0}:  whiten
+        next {0}
+",block);
+    }
+    source+="        end\n";
+    source
+}
+
+/// Runs `program` on a flag and returns the final accumulator, which is
+/// zero when the flag is accepted.
+///
+/// A flag longer than two words is followed in memory by a block of
+/// [`PADDING`] bytes: masked rules load the block at the pointer on every
+/// opcode, including after the last block has moved it past the flag.
+pub fn check_flag(rules:&[Rule],encoding:Encoding,vm:&VmRegs,program:&[u8],flag:&[u64],opaque:(u64,u64))->Result<u64,VmError>{
+    let mut regs=Registers::new();
+    let mut memory=Memory::new();
+    // what the harness leaves in the registers it does not load
+    regs.insert(vm.ptr, opaque.0);
+    regs.insert(vm.opaque_a, opaque.0);
+    regs.insert(vm.opaque_b, opaque.1);
+    regs.insert(vm.acc, 0);
+    if let [a,b]=*flag {
+        regs.insert(vm.flag_a, a);
+        regs.insert(vm.flag_b, b);
+    } else {
+        regs.insert(vm.flag_a, opaque.1);
+        regs.insert(vm.flag_b, opaque.0);
+        let padding=[0;PADDING].into_iter();
+        for (k,byte) in flag.iter().flat_map(|word|word.to_le_bytes()).chain(padding).enumerate() {
+            memory.insert(FLAG_ADDRESS+k as u64, byte);
+        }
+        regs.insert(vm.ptr, FLAG_ADDRESS);
+    }
+    let regs=run(rules, encoding, vm, program, regs, &memory)?;
+    Ok(regs[&vm.acc])
+}
+
+/// Makes sure the rules running `program` accept `flag` and reject [`WRONG_FLAGS`] others: one
+/// a single bit away from it, the rest random. The opaque registers get fresh
+/// random values on every run.
+pub fn verify<R:Rng+?Sized>(rules:&[Rule],encoding:Encoding,vm:&VmRegs,program:&[u8],flag:&[u64],rng:&mut R)->Result<(),VmError>{
+    let acc=check_flag(rules, encoding, vm, program, flag, rng.gen())?;
+    if acc!=0 {
+        return Err(VmError::FlagRejected(acc));
+    }
+    let bit=rng.gen_range(0..64*flag.len() as u64) as usize;
+    let mut flipped=flag.to_vec();
+    flipped[bit/64]^=1<<(bit%64);
+    let mut wrong=vec![flipped];
+    while wrong.len()<WRONG_FLAGS {
+        let other:Vec<u64>=flag.iter().map(|_|rng.gen()).collect();
+        if other!=flag {
+            wrong.push(other);
+        }
+    }
+    for other in wrong {
+        if check_flag(rules, encoding, vm, program, &other, rng.gen())?==0 {
+            return Err(VmError::WrongFlagAccepted(other));
+        }
+    }
+    Ok(())
+}